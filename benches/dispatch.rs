@@ -0,0 +1,51 @@
+//! Compares the prepared dispatch used by `MachineState::run` with the plain
+//! interpreter behind `MachineState::step`.
+//!
+//! Run with `cargo bench --bench dispatch`.
+
+use aaron_asm::{MachineState, Program};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ROUNDS: u32 = 2000;
+
+fn time(program: &Program, run: fn(&Program) -> bool) -> Duration {
+    // Warm up once so the first round doesn't pay for page faults.
+    assert!(run(program));
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        black_box(run(black_box(program)));
+    }
+    start.elapsed()
+}
+
+fn compiled(program: &Program) -> bool {
+    let mut machine = MachineState::new_null();
+    machine.run(program).is_ok()
+}
+
+fn interpreted(program: &Program) -> bool {
+    let mut machine = MachineState::new_null();
+    loop {
+        match machine.step(program) {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(_) => return false,
+        }
+    }
+}
+
+fn main() {
+    let program: Program = include_str!("../testcase/fibonacci.asm")
+        .parse()
+        .expect("fibonacci.asm assembles");
+    let compiled = time(&program, compiled);
+    let interpreted = time(&program, interpreted);
+    println!(
+        "fibonacci x{}: compiled {:?}, interpreted {:?} ({:.2}x)",
+        ROUNDS,
+        compiled,
+        interpreted,
+        interpreted.as_secs_f64() / compiled.as_secs_f64()
+    );
+}
@@ -0,0 +1,345 @@
+use crate::syntax_tree::{Address, Index, Number, Program, Statement, Value};
+use num_traits::ToPrimitive;
+use std::fmt;
+
+// Layout: the magic, then for every instruction its source line as a
+// little-endian u32, an opcode byte and the operands. Each operand is a tag
+// byte followed by a length-prefixed little-endian two's complement number
+// (or UTF-8 text for an unresolved label, which reading rejects).
+const MAGIC: &[u8] = b"AARN\x01";
+
+const OP_INCR: u8 = 0;
+const OP_DECR: u8 = 1;
+const OP_SAVE: u8 = 2;
+const OP_PUTC: u8 = 3;
+const OP_PUTN: u8 = 4;
+const OP_PUTH: u8 = 5;
+const OP_PUTS: u8 = 6;
+const OP_GETS: u8 = 7;
+const OP_HALT: u8 = 8;
+const OP_EXT: u8 = 9;
+const OP_NOP: u8 = 10;
+const OP_CALL: u8 = 12;
+const OP_RET: u8 = 13;
+// `putn` with a base other than 10; older bytecode only has `OP_PUTN`.
+const OP_PUTN_BASE: u8 = 14;
+const OP_DECRB: u8 = 15;
+const OP_SAVEPC: u8 = 16;
+const OP_LOADCODE: u8 = 17;
+const OP_ECHO: u8 = 18;
+// Not an instruction: a `data` block, written after the instructions.
+const OP_DATA: u8 = 11;
+
+const TAG_IMMEDIATE: u8 = 0;
+const TAG_REGISTER: u8 = 1;
+const TAG_POINTER: u8 = 2;
+const TAG_PROGRAM_COUNTER: u8 = 3;
+const TAG_LABEL: u8 = 4;
+
+#[derive(Debug, PartialEq)]
+pub enum BytecodeError {
+    InvalidMagic,
+    UnexpectedEnd,
+    InvalidOpcode(u8),
+    InvalidOperand(u8),
+    InvalidLabel,
+    /// A `decr` or `call` to an address outside the program.
+    InvalidJumpTarget(Number),
+    EmptyData,
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BytecodeError::InvalidMagic => write!(f, "Not an aaron-asm bytecode"),
+            BytecodeError::UnexpectedEnd => write!(f, "Unexpected end of bytecode"),
+            BytecodeError::InvalidOpcode(op) => write!(f, "Invalid opcode {}", op),
+            BytecodeError::InvalidOperand(tag) => write!(f, "Invalid operand tag {}", tag),
+            BytecodeError::InvalidLabel => write!(f, "Unresolved label in bytecode"),
+            BytecodeError::InvalidJumpTarget(n) => write!(f, "Invalid jump target {}", n),
+            BytecodeError::EmptyData => write!(f, "Data block without values"),
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+type Result<T> = std::result::Result<T, BytecodeError>;
+
+fn write_u32(buffer: &mut Vec<u8>, n: u32) {
+    buffer.extend_from_slice(&n.to_le_bytes());
+}
+
+fn write_payload(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(buffer, bytes.len() as u32);
+    buffer.extend_from_slice(bytes);
+}
+
+fn write_number(buffer: &mut Vec<u8>, tag: u8, n: &Number) {
+    buffer.push(tag);
+    write_payload(buffer, &n.to_signed_bytes_le());
+}
+
+fn write_label(buffer: &mut Vec<u8>, label: &str) {
+    buffer.push(TAG_LABEL);
+    write_payload(buffer, label.as_bytes());
+}
+
+fn write_index(buffer: &mut Vec<u8>, index: &Index) {
+    match index {
+        Index::Direct(n) => write_number(buffer, TAG_IMMEDIATE, n),
+        Index::Indirect(n) => write_number(buffer, TAG_REGISTER, n),
+        Index::Pointer(n) => write_number(buffer, TAG_POINTER, n),
+    }
+}
+
+fn write_value(buffer: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Immediate(n) => write_number(buffer, TAG_IMMEDIATE, n),
+        Value::Register(n) => write_number(buffer, TAG_REGISTER, n),
+        Value::Pointer(n) => write_number(buffer, TAG_POINTER, n),
+        Value::ProgramCounter => buffer.push(TAG_PROGRAM_COUNTER),
+        Value::Label(label) => write_label(buffer, label),
+    }
+}
+
+fn write_address(buffer: &mut Vec<u8>, address: &Address) {
+    match address {
+        Address::Immediate(n) => write_number(buffer, TAG_IMMEDIATE, n),
+        Address::Register(n) => write_number(buffer, TAG_REGISTER, n),
+        Address::ProgramCounter => buffer.push(TAG_PROGRAM_COUNTER),
+        Address::Label(label) => write_label(buffer, label),
+    }
+}
+
+fn write_statement(buffer: &mut Vec<u8>, statement: &Statement) {
+    match statement {
+        Statement::Incr(i, v) => {
+            buffer.push(OP_INCR);
+            write_index(buffer, i);
+            write_value(buffer, v);
+        }
+        Statement::Decr(i, a, v) => {
+            buffer.push(OP_DECR);
+            write_index(buffer, i);
+            write_address(buffer, a);
+            write_value(buffer, v);
+        }
+        Statement::Decrb(i, b, v) => {
+            buffer.push(OP_DECRB);
+            write_index(buffer, i);
+            write_index(buffer, b);
+            write_value(buffer, v);
+        }
+        Statement::Loadcode(i, a) => {
+            buffer.push(OP_LOADCODE);
+            write_index(buffer, i);
+            write_address(buffer, a);
+        }
+        Statement::Savepc(i) => {
+            buffer.push(OP_SAVEPC);
+            write_index(buffer, i);
+        }
+        Statement::Save(i, v) => {
+            buffer.push(OP_SAVE);
+            write_index(buffer, i);
+            write_value(buffer, v);
+        }
+        Statement::Putc(v) => {
+            buffer.push(OP_PUTC);
+            write_value(buffer, v);
+        }
+        Statement::Putn(v, Value::Immediate(base)) if *base == Number::from(10) => {
+            buffer.push(OP_PUTN);
+            write_value(buffer, v);
+        }
+        Statement::Putn(v, base) => {
+            buffer.push(OP_PUTN_BASE);
+            write_value(buffer, v);
+            write_value(buffer, base);
+        }
+        Statement::Puth(v) => {
+            buffer.push(OP_PUTH);
+            write_value(buffer, v);
+        }
+        Statement::Puts(i) => {
+            buffer.push(OP_PUTS);
+            write_index(buffer, i);
+        }
+        Statement::Gets(i) => {
+            buffer.push(OP_GETS);
+            write_index(buffer, i);
+        }
+        Statement::Echo => buffer.push(OP_ECHO),
+        Statement::Halt => buffer.push(OP_HALT),
+        Statement::Nop => buffer.push(OP_NOP),
+        Statement::Call(a) => {
+            buffer.push(OP_CALL);
+            write_address(buffer, a);
+        }
+        Statement::Ret => buffer.push(OP_RET),
+        Statement::Ext(n) => {
+            buffer.push(OP_EXT);
+            write_payload(buffer, &n.to_signed_bytes_le());
+        }
+    }
+}
+
+/// The instruction's bytecode, without its line number, read as a
+/// little-endian unsigned integer. The opcode is the lowest byte, so `halt`
+/// is 8 and `nop` is 10.
+pub(crate) fn instruction_code(statement: &Statement) -> Number {
+    let mut buffer = Vec::new();
+    write_statement(&mut buffer, statement);
+    Number::from_bytes_le(num_bigint::Sign::Plus, &buffer)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            return Err(BytecodeError::UnexpectedEnd);
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn payload(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn number(&mut self) -> Result<Number> {
+        Ok(Number::from_signed_bytes_le(self.payload()?))
+    }
+
+    fn index(&mut self) -> Result<Index> {
+        match self.byte()? {
+            TAG_IMMEDIATE => Ok(Index::Direct(self.number()?)),
+            TAG_REGISTER => Ok(Index::Indirect(self.number()?)),
+            TAG_POINTER => Ok(Index::Pointer(self.number()?)),
+            tag => Err(BytecodeError::InvalidOperand(tag)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.byte()? {
+            TAG_IMMEDIATE => Ok(Value::Immediate(self.number()?)),
+            TAG_REGISTER => Ok(Value::Register(self.number()?)),
+            TAG_POINTER => Ok(Value::Pointer(self.number()?)),
+            TAG_PROGRAM_COUNTER => Ok(Value::ProgramCounter),
+            TAG_LABEL => Err(BytecodeError::InvalidLabel),
+            tag => Err(BytecodeError::InvalidOperand(tag)),
+        }
+    }
+
+    fn address(&mut self) -> Result<Address> {
+        match self.byte()? {
+            TAG_IMMEDIATE => Ok(Address::Immediate(self.number()?)),
+            TAG_REGISTER => Ok(Address::Register(self.number()?)),
+            TAG_PROGRAM_COUNTER => Ok(Address::ProgramCounter),
+            TAG_LABEL => Err(BytecodeError::InvalidLabel),
+            tag => Err(BytecodeError::InvalidOperand(tag)),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement> {
+        Ok(match self.byte()? {
+            OP_INCR => Statement::Incr(self.index()?, self.value()?),
+            OP_DECR => Statement::Decr(self.index()?, self.address()?, self.value()?),
+            OP_SAVE => Statement::Save(self.index()?, self.value()?),
+            OP_SAVEPC => Statement::Savepc(self.index()?),
+            OP_LOADCODE => Statement::Loadcode(self.index()?, self.address()?),
+            OP_DECRB => Statement::Decrb(self.index()?, self.index()?, self.value()?),
+            OP_PUTC => Statement::Putc(self.value()?),
+            OP_PUTN => Statement::Putn(self.value()?, Value::Immediate(Number::from(10))),
+            OP_PUTN_BASE => Statement::Putn(self.value()?, self.value()?),
+            OP_PUTH => Statement::Puth(self.value()?),
+            OP_PUTS => Statement::Puts(self.index()?),
+            OP_GETS => Statement::Gets(self.index()?),
+            OP_ECHO => Statement::Echo,
+            OP_HALT => Statement::Halt,
+            OP_NOP => Statement::Nop,
+            OP_CALL => Statement::Call(self.address()?),
+            OP_RET => Statement::Ret,
+            OP_EXT => Statement::Ext(self.number()?),
+            op => return Err(BytecodeError::InvalidOpcode(op)),
+        })
+    }
+}
+
+impl Program {
+    pub fn to_bytecode(&self) -> Vec<u8> {
+        let mut buffer = MAGIC.to_vec();
+        for (pc, statement) in self.iter().enumerate() {
+            write_u32(&mut buffer, self.line(pc).unwrap_or(0) as u32);
+            write_statement(&mut buffer, statement);
+        }
+        for (index, values) in self.data() {
+            write_u32(&mut buffer, 0);
+            buffer.push(OP_DATA);
+            write_u32(&mut buffer, *index as u32);
+            write_u32(&mut buffer, values.len() as u32);
+            for value in values {
+                write_payload(&mut buffer, &value.to_signed_bytes_le());
+            }
+        }
+        buffer
+    }
+
+    pub fn from_bytecode(bytes: &[u8]) -> Result<Program> {
+        let bytes = bytes
+            .strip_prefix(MAGIC)
+            .ok_or(BytecodeError::InvalidMagic)?;
+        let mut reader = Reader { bytes };
+        let mut statements = Vec::new();
+        let mut lines = Vec::new();
+        let mut data = Vec::new();
+        while !reader.bytes.is_empty() {
+            let line = reader.u32()? as usize;
+            if reader.bytes.first() == Some(&OP_DATA) {
+                reader.byte()?;
+                let index = reader.u32()? as usize;
+                let count = reader.u32()?;
+                if count == 0 {
+                    return Err(BytecodeError::EmptyData);
+                }
+                let values = (0..count)
+                    .map(|_| reader.number())
+                    .collect::<Result<Vec<_>>>()?;
+                data.push((index, values));
+                continue;
+            }
+            lines.push(line);
+            statements.push(reader.statement()?);
+        }
+        // The bytes may come from anywhere, so a fixed jump target has to
+        // be inside the program or just past its end.
+        for statement in &statements {
+            if let Statement::Decr(_, Address::Immediate(n), _)
+            | Statement::Call(Address::Immediate(n)) = statement
+            {
+                if !matches!(n.to_usize(), Some(n) if n <= statements.len()) {
+                    return Err(BytecodeError::InvalidJumpTarget(n.clone()));
+                }
+            }
+        }
+        let mut program = Program::from_parts(statements, lines);
+        program.set_data(data);
+        Ok(program)
+    }
+}
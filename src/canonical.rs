@@ -0,0 +1,80 @@
+use crate::syntax_tree::{Address, Index, Program, Statement, Value};
+
+// The first line of the canonical text. Bump the number whenever the format
+// below changes so that stored listings are not compared across formats.
+const HEADER: &str = "; aaron-asm canonical 1";
+
+fn index(index: &Index) -> String {
+    match index {
+        Index::Direct(n) => n.to_string(),
+        Index::Indirect(n) => format!("[{}]", n),
+        Index::Pointer(n) => format!("[[{}]]", n),
+    }
+}
+
+fn value(value: &Value) -> String {
+    match value {
+        Value::Immediate(n) => n.to_string(),
+        Value::Register(n) => format!("[{}]", n),
+        Value::Pointer(n) => format!("[[{}]]", n),
+        Value::ProgramCounter => String::from("pc"),
+        Value::Label(label) => label.clone(),
+    }
+}
+
+fn address(address: &Address) -> String {
+    match address {
+        Address::Immediate(n) => n.to_string(),
+        Address::Register(n) => format!("[{}]", n),
+        Address::ProgramCounter => String::from("pc"),
+        Address::Label(label) => label.clone(),
+    }
+}
+
+fn operands(statement: &Statement) -> Vec<String> {
+    match statement {
+        Statement::Incr(i, v) | Statement::Save(i, v) => vec![index(i), value(v)],
+        Statement::Decr(i, a, v) => vec![index(i), address(a), value(v)],
+        Statement::Decrb(i, b, v) => vec![index(i), index(b), value(v)],
+        Statement::Putc(v) | Statement::Puth(v) => vec![value(v)],
+        Statement::Putn(v, base) => vec![value(v), value(base)],
+        Statement::Puts(i) | Statement::Gets(i) | Statement::Savepc(i) => vec![index(i)],
+        Statement::Call(a) => vec![address(a)],
+        Statement::Loadcode(i, a) => vec![index(i), address(a)],
+        Statement::Ext(n) => vec![n.to_string()],
+        Statement::Halt | Statement::Nop | Statement::Echo | Statement::Ret => vec![],
+    }
+}
+
+impl Program {
+    /// A listing meant for diffing across versions rather than for reading.
+    /// Unlike `Display`, its layout is fixed: a version header, the `data`
+    /// blocks, then one line per instruction with a four digit address, the
+    /// mnemonic padded to seven columns and every operand spelled out,
+    /// defaults included.
+    pub fn to_canonical_text(&self) -> String {
+        let mut text = String::from(HEADER);
+        text.push('\n');
+        for (index, values) in self.data() {
+            text.push_str(&format!("data {}", index));
+            for value in values {
+                text.push_str(&format!(", {}", value));
+            }
+            text.push('\n');
+        }
+        for (pc, statement) in self.iter().enumerate() {
+            let operands = operands(statement);
+            if operands.is_empty() {
+                text.push_str(&format!("{:04}  {}\n", pc, statement.mnemonic()));
+            } else {
+                text.push_str(&format!(
+                    "{:04}  {:<7} {}\n",
+                    pc,
+                    statement.mnemonic(),
+                    operands.join(", ")
+                ));
+            }
+        }
+        text
+    }
+}
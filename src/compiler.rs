@@ -1,4 +1,5 @@
 use crate::syntax_tree::*;
+use num_traits::ToPrimitive;
 
 #[derive(Debug)]
 pub enum ParseError {
@@ -6,17 +7,120 @@ pub enum ParseError {
     InvalidIdentifier,
     LabelOnly,
     UnknownMnemonic,
+    /// A mnemonic directly followed by its operand, as in `incr0, 1`.
+    MissingSpace(&'static str),
     UnclosedBracket,
+    UnexpectedCloseBracket(char),
     ExpectInteger,
     ExpectValue,
     ExtraZero,
     ExtraOperand,
-    TooFewArguments,
+    /// A `,` with nothing after it.
+    MissingOperand,
+    /// The mnemonic and the number of operands found before the missing one.
+    TooFewArguments(&'static str, usize),
     ExpectAddress,
+    ExpectIndex,
+    ProgramCounterIndex,
+    InvalidRepeatCount,
+    InvalidAlignment,
+    MissingConstantName,
+    UnexpectedOperand(&'static str),
+    ImplicitOperand(&'static str),
+    InvalidEscape,
+    UnterminatedCharLiteral,
+    EmptyCharLiteral,
+    UnterminatedString,
+    /// A `data` or `equ` line followed by `:` and another statement.
+    NotAlone(&'static str),
     EndOfProgram,
 }
 
-type ParseResult<'a, T> = std::result::Result<(T, &'a str), ParseError>;
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::InvalidLabel => write!(f, "Invalid label"),
+            ParseError::InvalidIdentifier => write!(f, "Invalid identifier"),
+            ParseError::LabelOnly => write!(f, "Label without instruction"),
+            ParseError::UnknownMnemonic => write!(f, "Unknown mnemonic"),
+            ParseError::MissingSpace(mnemonic) => {
+                write!(f, "Missing space between {} and its operand", mnemonic)
+            }
+            ParseError::UnclosedBracket => write!(f, "Unclosed bracket"),
+            ParseError::UnexpectedCloseBracket(ch) => write!(f, "Unexpected '{}'", ch),
+            ParseError::ExpectInteger => write!(f, "Expected an integer"),
+            ParseError::ExpectValue => write!(f, "Expected a value"),
+            ParseError::ExtraZero => write!(f, "Integer has a leading zero"),
+            ParseError::ExtraOperand => write!(f, "Too many operands"),
+            ParseError::MissingOperand => write!(f, "Expected an operand after ','"),
+            ParseError::TooFewArguments(mnemonic, found) => {
+                write!(f, "{}", mnemonic)?;
+                if let Some(info) = MNEMONICS.iter().find(|info| info.name == *mnemonic) {
+                    write!(f, " expects {}", info.signature())?;
+                }
+                write!(
+                    f,
+                    " but found only {} operand{}",
+                    found,
+                    if *found == 1 { "" } else { "s" }
+                )
+            }
+            ParseError::ExpectAddress => write!(f, "Expected an address"),
+            ParseError::ExpectIndex => write!(
+                f,
+                "Expected a register index; a label or pc can not be used as the index operand"
+            ),
+            ParseError::ProgramCounterIndex => {
+                write!(
+                    f,
+                    "pc can only be used as a value or an address, not as a register index"
+                )
+            }
+            ParseError::MissingConstantName => write!(f, "equ needs a name in the label field"),
+            ParseError::InvalidAlignment => write!(
+                f,
+                "Alignment must be a positive integer that keeps the program within {} instructions",
+                MAX_PROGRAM_LEN
+            ),
+            ParseError::InvalidRepeatCount => write!(
+                f,
+                "Repeat count must be a non-negative integer that keeps the program within {} instructions",
+                MAX_PROGRAM_LEN
+            ),
+            ParseError::UnexpectedOperand(mnemonic) => {
+                write!(f, "{} does not take operands", mnemonic)
+            }
+            ParseError::ImplicitOperand(mnemonic) => {
+                write!(
+                    f,
+                    "{} needs an explicit value operand in strict mode",
+                    mnemonic
+                )
+            }
+            ParseError::InvalidEscape => write!(f, "Invalid escape sequence"),
+            ParseError::UnterminatedCharLiteral => write!(f, "Unterminated character literal"),
+            ParseError::EmptyCharLiteral => write!(f, "Empty character literal"),
+            ParseError::UnterminatedString => write!(f, "Unterminated string literal"),
+            ParseError::NotAlone(directive) => {
+                write!(f, "{} can not share a line with other statements", directive)
+            }
+            ParseError::EndOfProgram => write!(f, "End of program"),
+        }
+    }
+}
+
+type Failure<'a> = (ParseError, &'a str);
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Grammar {
+    /// Accept `(n)` and `((n))` as the same as `[n]` and `[[n]]`.
+    pub paren_indirect: bool,
+    /// Require the value operand of `incr` and `decr` instead of defaulting
+    /// it to 1.
+    pub strict: bool,
+}
+
+type ParseResult<'a, T> = std::result::Result<(T, &'a str), Failure<'a>>;
 
 fn is_space(ch: char) -> bool {
     ch == ' ' || ch == '\t'
@@ -51,7 +155,7 @@ fn parse_skip(input: &str, predicate: impl Fn(char) -> bool) -> &str {
 }
 
 fn parse_skip_until(input: &str, predicate: impl Fn(char) -> bool) -> &str {
-    if let Some(pos) = input.find(|ch| predicate(ch)) {
+    if let Some(pos) = input.find(predicate) {
         let mut iter = input[pos..].chars();
         iter.next();
         iter.as_str()
@@ -64,95 +168,287 @@ fn skip_space(input: &str) -> &str {
     parse_skip(input, |ch| ch == ' ' || ch == '\t')
 }
 
+// Whether the rest of the line holds nothing but an optional comment.
+fn is_comment(input: &str) -> bool {
+    input.starts_with(';') || input.starts_with("//")
+}
+
+fn is_blank(input: &str) -> bool {
+    is_comment(input) || matches!(input.chars().next(), None | Some('\r') | Some('\n'))
+}
+
 fn skip_comment(input: &str) -> &str {
     parse_skip_until(input, |ch| ch == '\n')
 }
 
-fn parse_label(input: &str) -> ParseResult<Option<String>> {
+// Consumes the rest of a blank line, returning the comment on it if any.
+fn parse_line_end(input: &str) -> (Option<&str>, &str) {
+    let (text, _) = parse_while(input, |ch| ch != '\n');
+    let text = text.strip_suffix('\r').unwrap_or(text);
+    let comment = if is_comment(text) { Some(text) } else { None };
+    (comment, skip_comment(input))
+}
+
+// Consumes the rest of the line that begins at `start`, returning the comment
+// on it with where it starts.
+fn parse_comment<'a>(start: &str, rest: &'a str, line: usize) -> (Option<Comment>, &'a str) {
+    let (text, next) = parse_line_end(rest);
+    let comment = text.map(|text| Comment {
+        line,
+        column: start.len() - rest.len() + 1,
+        text: text.to_string(),
+    });
+    (comment, next)
+}
+
+// The text from `start` up to `rest` as the formatter writes it: runs of
+// spaces and tabs become one space, a `,` is followed by one space and a `:`
+// has one on each side. Character and string literals are kept as they are.
+fn written(start: &str, rest: &str) -> String {
+    let mut output = String::new();
+    let mut space = false;
+    let mut chars = start[..start.len() - rest.len()].chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            ' ' | '\t' => space = true,
+            ',' => {
+                output.push(',');
+                space = true;
+            }
+            ':' => {
+                output.push_str(" :");
+                space = true;
+            }
+            _ => {
+                if space && !output.is_empty() {
+                    output.push(' ');
+                }
+                space = false;
+                output.push(ch);
+                if ch == '\'' || ch == '"' {
+                    while let Some(next) = chars.next() {
+                        output.push(next);
+                        if next == '\\' {
+                            output.extend(chars.next());
+                        } else if next == ch {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    output
+}
+
+fn parse_label(input: &str) -> ParseResult<'_, Option<String>> {
     match parse_one(input, |_| true) {
         Some((ch, _)) if ch.is_ascii_alphabetic() => {
             let (label, rest) = parse_while(input, |ch| ch.is_ascii_alphanumeric());
-            Ok((Some(String::from_str(label).unwrap()), rest))
+            Ok((Some(label.to_string()), rest))
         }
-        Some((ch, _)) if is_space(ch) || ch == ';' || ch == '\r' || ch == '\n' => Ok((None, input)),
-        Some(_) => Err(ParseError::InvalidLabel),
+        Some((ch, _)) if is_space(ch) || is_blank(input) => Ok((None, input)),
+        Some(_) => Err((ParseError::InvalidLabel, input)),
         None => Ok((None, input)),
     }
 }
 
-fn parse_identifier(input: &str) -> ParseResult<String> {
-    let _ = parse_one(input, |ch| ch.is_ascii_alphabetic()).ok_or(ParseError::InvalidIdentifier);
+fn parse_identifier(input: &str) -> ParseResult<'_, String> {
+    parse_one(input, |ch| ch.is_ascii_alphabetic())
+        .ok_or((ParseError::InvalidIdentifier, input))?;
     let (label, rest) = parse_while(input, |ch| ch.is_ascii_alphanumeric());
-    Ok((String::from_str(label).unwrap(), rest))
+    Ok((label.to_string(), rest))
 }
 
+#[derive(Clone, Copy)]
 enum Mnemonic {
     Incr,
     Decr,
+    Decrb,
     Save,
+    Savepc,
+    Loadcode,
     Putc,
     Putn,
+    Puth,
+    Puts,
+    Gets,
+    Echo,
     Halt,
+    Syscall,
+    Nop,
+    Call,
+    Ret,
 }
 
-fn parse_mnemonic(input: &str) -> ParseResult<Mnemonic> {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperandKind {
+    Index,
+    Address,
+    Value,
+    Integer,
+}
+
+pub struct MnemonicInfo {
+    pub name: &'static str,
+    pub operand_count: usize,
+    pub required_operands: usize,
+    pub operand_kinds: &'static [OperandKind],
+    mnemonic: Mnemonic,
+}
+
+impl MnemonicInfo {
+    /// Operand list such as `INDEX, ADDRESS[, VALUE]`, with optional
+    /// operands in brackets.
+    pub fn signature(&self) -> String {
+        let mut signature = String::new();
+        for (i, kind) in self.operand_kinds.iter().enumerate() {
+            let name = match kind {
+                OperandKind::Index => "INDEX",
+                OperandKind::Address => "ADDRESS",
+                OperandKind::Value => "VALUE",
+                OperandKind::Integer => "INTEGER",
+            };
+            let separator = if i == 0 { "" } else { ", " };
+            if i < self.required_operands {
+                signature.push_str(separator);
+                signature.push_str(name);
+            } else {
+                signature.push('[');
+                signature.push_str(separator);
+                signature.push_str(name);
+                signature.push(']');
+            }
+        }
+        signature
+    }
+}
+
+const fn info(
+    name: &'static str,
+    mnemonic: Mnemonic,
+    required_operands: usize,
+    operand_kinds: &'static [OperandKind],
+) -> MnemonicInfo {
+    MnemonicInfo {
+        name,
+        operand_count: operand_kinds.len(),
+        required_operands,
+        operand_kinds,
+        mnemonic,
+    }
+}
+
+use OperandKind::{Address as A, Index as I, Integer as N, Value as V};
+
+const MNEMONICS: &[MnemonicInfo] = &[
+    info("incr", Mnemonic::Incr, 1, &[I, V]),
+    info("decr", Mnemonic::Decr, 2, &[I, A, V]),
+    info("decrb", Mnemonic::Decrb, 3, &[I, I, V]),
+    info("save", Mnemonic::Save, 2, &[I, V]),
+    info("savepc", Mnemonic::Savepc, 1, &[I]),
+    info("loadcode", Mnemonic::Loadcode, 2, &[I, A]),
+    info("putc", Mnemonic::Putc, 1, &[V]),
+    info("putn", Mnemonic::Putn, 1, &[V, V]),
+    info("puth", Mnemonic::Puth, 1, &[V]),
+    info("puts", Mnemonic::Puts, 1, &[I]),
+    info("gets", Mnemonic::Gets, 1, &[I]),
+    info("echo", Mnemonic::Echo, 0, &[]),
+    info("halt", Mnemonic::Halt, 0, &[]),
+    info("syscall", Mnemonic::Syscall, 1, &[N]),
+    info("nop", Mnemonic::Nop, 0, &[]),
+    info("call", Mnemonic::Call, 1, &[A]),
+    info("ret", Mnemonic::Ret, 0, &[]),
+];
+
+pub fn mnemonics() -> &'static [MnemonicInfo] {
+    MNEMONICS
+}
+
+fn parse_mnemonic(input: &str) -> ParseResult<'_, Mnemonic> {
     let (mnemonic, rest) = parse_while(input, |ch| ch.is_ascii_alphanumeric());
-    Ok((
-        match mnemonic {
-            "incr" => Mnemonic::Incr,
-            "decr" => Mnemonic::Decr,
-            "save" => Mnemonic::Save,
-            "putc" => Mnemonic::Putc,
-            "putn" => Mnemonic::Putn,
-            "halt" => Mnemonic::Halt,
-            _ => Err(ParseError::UnknownMnemonic)?,
-        },
-        rest,
-    ))
-}
-
-fn skip_extra_field(input: &str) -> std::result::Result<&str, ParseError> {
-    let rest = skip_space(input);
-    match rest.chars().next() {
-        Some(ch) if ch == ';' || ch == '\n' || ch == '\r' => Ok(skip_comment(rest)),
-        Some(_) => Err(ParseError::ExtraOperand),
-        None => Ok(rest),
+    if let Some(info) = MNEMONICS.iter().find(|info| info.name == mnemonic) {
+        return Ok((info.mnemonic, rest));
+    }
+    let glued = MNEMONICS.iter().find(|info| {
+        mnemonic.starts_with(info.name)
+            && mnemonic[info.name.len()..].starts_with(|ch: char| ch.is_ascii_digit())
+    });
+    match glued {
+        Some(info) => Err((
+            ParseError::MissingSpace(info.name),
+            &input[info.name.len()..],
+        )),
+        None => Err((ParseError::UnknownMnemonic, input)),
     }
 }
 
-fn parse_operand_separator(input: &str) -> std::result::Result<&str, ParseError> {
+fn skip_extra_field(input: &str) -> std::result::Result<&str, Failure<'_>> {
     let rest = skip_space(input);
-    let (_, rest) = parse_one(rest, |ch| ch == ',').ok_or(ParseError::TooFewArguments)?;
+    if is_blank(rest) || rest.starts_with(':') {
+        Ok(rest)
+    } else {
+        Err((ParseError::ExtraOperand, rest))
+    }
+}
+
+fn parse_operand_separator<'a>(
+    input: &'a str,
+    mnemonic: &'static str,
+    found: usize,
+) -> std::result::Result<&'a str, Failure<'a>> {
+    let separator = skip_space(input);
+    let (_, rest) = parse_one(separator, |ch| ch == ',')
+        .ok_or((ParseError::TooFewArguments(mnemonic, found), separator))?;
     let rest = skip_space(rest);
+    if is_blank(rest) || rest.starts_with(':') {
+        return Err((ParseError::MissingOperand, separator));
+    }
     Ok(rest)
 }
 
-fn parse_incr_operand(input: &str) -> ParseResult<Statement> {
-    let (index, rest) = parse_index(input)?;
-    match parse_operand_separator(rest) {
-        Err(_) => Ok((
-            Statement::Incr(index, Value::Immediate(Number::from(1))),
-            rest,
-        )),
+// `incr i` is `incr i, 1`. A trailing comma without a value is an error.
+fn parse_incr_operand(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
+    let (index, rest) = parse_index(input, grammar)?;
+    match parse_operand_separator(rest, "incr", 1) {
+        Err(e @ (ParseError::MissingOperand, _)) => Err(e),
+        Err(_) => {
+            let rest = skip_extra_field(rest)?;
+            if grammar.strict {
+                return Err((ParseError::ImplicitOperand("incr"), rest));
+            }
+            Ok((
+                Statement::Incr(index, Value::Immediate(Number::from(1))),
+                rest,
+            ))
+        }
         Ok(rest) => {
-            let (value, rest) = parse_value(rest)?;
+            let (value, rest) = parse_value(rest, grammar)?;
             let rest = skip_space(rest);
             Ok((Statement::Incr(index, value), skip_extra_field(rest)?))
         }
     }
 }
 
-fn parse_decr_operand(input: &str) -> ParseResult<Statement> {
-    let (index, rest) = parse_index(input)?;
-    let rest = parse_operand_separator(rest)?;
-    let (address, rest) = parse_address(rest)?;
-    match parse_operand_separator(rest) {
-        Err(_) => Ok((
-            Statement::Decr(index, address, Value::Immediate(Number::from(1))),
-            skip_extra_field(rest)?,
-        )),
+// `decr i, a` is `decr i, a, 1`. A trailing comma without a value is an error.
+fn parse_decr_operand(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
+    let (index, rest) = parse_index(input, grammar)?;
+    let rest = parse_operand_separator(rest, "decr", 1)?;
+    let (address, rest) = parse_address(rest, grammar)?;
+    match parse_operand_separator(rest, "decr", 2) {
+        Err(e @ (ParseError::MissingOperand, _)) => Err(e),
+        Err(_) => {
+            let rest = skip_extra_field(rest)?;
+            if grammar.strict {
+                return Err((ParseError::ImplicitOperand("decr"), rest));
+            }
+            Ok((
+                Statement::Decr(index, address, Value::Immediate(Number::from(1))),
+                rest,
+            ))
+        }
         Ok(rest) => {
-            let (value, rest) = parse_value(rest)?;
+            let (value, rest) = parse_value(rest, grammar)?;
             Ok((
                 Statement::Decr(index, address, value),
                 skip_extra_field(rest)?,
@@ -161,175 +457,791 @@ fn parse_decr_operand(input: &str) -> ParseResult<Statement> {
     }
 }
 
-fn parse_save_operand(input: &str) -> ParseResult<Statement> {
-    let (index, rest) = parse_index(input)?;
-    let rest = parse_operand_separator(rest)?;
-    let (value, rest) = parse_value(rest)?;
+fn parse_decrb_operand(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
+    let (index, rest) = parse_index(input, grammar)?;
+    let rest = parse_operand_separator(rest, "decrb", 1)?;
+    let (borrow, rest) = parse_index(rest, grammar)?;
+    let rest = parse_operand_separator(rest, "decrb", 2)?;
+    let (value, rest) = parse_value(rest, grammar)?;
+    let rest = skip_extra_field(rest)?;
+    Ok((Statement::Decrb(index, borrow, value), rest))
+}
+
+fn parse_save_operand(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
+    let (index, rest) = parse_index(input, grammar)?;
+    let rest = parse_operand_separator(rest, "save", 1)?;
+    let (value, rest) = parse_value(rest, grammar)?;
     let rest = skip_extra_field(rest)?;
     Ok((Statement::Save(index, value), rest))
 }
 
-fn parse_putc_operand(input: &str) -> ParseResult<Statement> {
-    let (value, rest) = parse_value(input)?;
+fn parse_savepc_operand(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
+    let (index, rest) = parse_index(input, grammar)?;
+    let rest = skip_extra_field(rest)?;
+    Ok((Statement::Savepc(index), rest))
+}
+
+fn parse_loadcode_operand(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
+    let (index, rest) = parse_index(input, grammar)?;
+    let rest = parse_operand_separator(rest, "loadcode", 1)?;
+    let (address, rest) = parse_address(rest, grammar)?;
+    let rest = skip_extra_field(rest)?;
+    Ok((Statement::Loadcode(index, address), rest))
+}
+
+fn parse_putc_operand(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
+    let (value, rest) = parse_value(input, grammar)?;
     let rest = skip_extra_field(rest)?;
     Ok((Statement::Putc(value), rest))
 }
 
-fn parse_putn_operand(input: &str) -> ParseResult<Statement> {
-    let (value, rest) = parse_value(input)?;
+// `putn v` is `putn v, 10`.
+fn parse_putn_operand(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
+    let (value, rest) = parse_value(input, grammar)?;
+    match parse_operand_separator(rest, "putn", 1) {
+        Err(e @ (ParseError::MissingOperand, _)) => Err(e),
+        Err(_) => {
+            let rest = skip_extra_field(rest)?;
+            Ok((
+                Statement::Putn(value, Value::Immediate(Number::from(10))),
+                rest,
+            ))
+        }
+        Ok(rest) => {
+            let (base, rest) = parse_value(rest, grammar)?;
+            Ok((Statement::Putn(value, base), skip_extra_field(rest)?))
+        }
+    }
+}
+
+fn parse_puth_operand(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
+    let (value, rest) = parse_value(input, grammar)?;
+    let rest = skip_extra_field(rest)?;
+    Ok((Statement::Puth(value), rest))
+}
+
+fn parse_puts_operand(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
+    let (index, rest) = parse_index(input, grammar)?;
+    let rest = skip_extra_field(rest)?;
+    Ok((Statement::Puts(index), rest))
+}
+
+fn parse_gets_operand(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
+    let (index, rest) = parse_index(input, grammar)?;
     let rest = skip_extra_field(rest)?;
-    Ok((Statement::Putn(value), rest))
+    Ok((Statement::Gets(index), rest))
+}
+
+fn skip_no_operand<'a>(
+    input: &'a str,
+    mnemonic: &'static str,
+) -> std::result::Result<&'a str, Failure<'a>> {
+    skip_extra_field(input).map_err(|(_, rest)| (ParseError::UnexpectedOperand(mnemonic), rest))
 }
 
-fn parse_halt_operand(input: &str) -> ParseResult<Statement> {
-    let rest = skip_extra_field(input)?;
+fn parse_halt_operand(input: &str) -> ParseResult<'_, Statement> {
+    let rest = skip_no_operand(input, "halt")?;
     Ok((Statement::Halt, rest))
 }
 
-fn parse_command(input: &str) -> ParseResult<Statement> {
+fn parse_echo_operand(input: &str) -> ParseResult<'_, Statement> {
+    let rest = skip_no_operand(input, "echo")?;
+    Ok((Statement::Echo, rest))
+}
+
+fn parse_nop_operand(input: &str) -> ParseResult<'_, Statement> {
+    let rest = skip_no_operand(input, "nop")?;
+    Ok((Statement::Nop, rest))
+}
+
+fn parse_call_operand(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
+    let (address, rest) = parse_address(input, grammar)?;
+    let rest = skip_extra_field(rest)?;
+    Ok((Statement::Call(address), rest))
+}
+
+fn parse_ret_operand(input: &str) -> ParseResult<'_, Statement> {
+    let rest = skip_no_operand(input, "ret")?;
+    Ok((Statement::Ret, rest))
+}
+
+fn parse_syscall_operand(input: &str) -> ParseResult<'_, Statement> {
+    let (number, rest) = parse_integer(input)?;
+    let rest = skip_extra_field(rest)?;
+    Ok((Statement::Ext(number), rest))
+}
+
+fn parse_command(input: &str, grammar: Grammar) -> ParseResult<'_, Statement> {
     let (mnemonic, rest) = parse_mnemonic(input)?;
     let rest = skip_space(rest);
     match mnemonic {
-        Mnemonic::Incr => parse_incr_operand(rest),
-        Mnemonic::Decr => parse_decr_operand(rest),
-        Mnemonic::Save => parse_save_operand(rest),
-        Mnemonic::Putc => parse_putc_operand(rest),
-        Mnemonic::Putn => parse_putn_operand(rest),
+        Mnemonic::Incr => parse_incr_operand(rest, grammar),
+        Mnemonic::Decr => parse_decr_operand(rest, grammar),
+        Mnemonic::Decrb => parse_decrb_operand(rest, grammar),
+        Mnemonic::Save => parse_save_operand(rest, grammar),
+        Mnemonic::Savepc => parse_savepc_operand(rest, grammar),
+        Mnemonic::Loadcode => parse_loadcode_operand(rest, grammar),
+        Mnemonic::Putc => parse_putc_operand(rest, grammar),
+        Mnemonic::Putn => parse_putn_operand(rest, grammar),
+        Mnemonic::Puth => parse_puth_operand(rest, grammar),
+        Mnemonic::Puts => parse_puts_operand(rest, grammar),
+        Mnemonic::Gets => parse_gets_operand(rest, grammar),
+        Mnemonic::Echo => parse_echo_operand(rest),
         Mnemonic::Halt => parse_halt_operand(rest),
+        Mnemonic::Syscall => parse_syscall_operand(rest),
+        Mnemonic::Nop => parse_nop_operand(rest),
+        Mnemonic::Call => parse_call_operand(rest, grammar),
+        Mnemonic::Ret => parse_ret_operand(rest),
     }
 }
 
-fn parse_integer(input: &str) -> ParseResult<Number> {
+// `times N instruction` stands for N copies of the instruction and `align N`
+// for as many `nop`s as it takes to reach the next multiple of N. Both are
+// expanded here so that label addresses count every generated instruction.
+fn parse_repeated(
+    input: &str,
+    grammar: Grammar,
+    address: usize,
+) -> ParseResult<'_, Vec<Statement>> {
+    let (word, rest) = parse_while(input, |ch| ch.is_ascii_alphanumeric());
+    match word {
+        "times" => {}
+        "align" => return parse_align(skip_space(rest), address),
+        _ => {
+            let (statement, rest) = parse_command(input, grammar)?;
+            return Ok((vec![statement], rest));
+        }
+    }
+    let rest = skip_space(rest);
+    let (count, next) = parse_integer(rest)?;
+    let count = count
+        .to_usize()
+        .filter(|&count| address.saturating_add(count) <= MAX_PROGRAM_LEN)
+        .ok_or((ParseError::InvalidRepeatCount, rest))?;
+    let (statement, rest) = parse_command(skip_space(next), grammar)?;
+    Ok((vec![statement; count], rest))
+}
+
+// The most instructions `times` and `align` may bring a program up to, so a
+// large count in the source can't exhaust memory while assembling.
+const MAX_PROGRAM_LEN: usize = crate::vm::MEMORY_LIMIT;
+
+fn parse_align(input: &str, address: usize) -> ParseResult<'_, Vec<Statement>> {
+    let (n, rest) = parse_integer(input)?;
+    let n = n
+        .to_usize()
+        .filter(|&n| n > 0 && address.saturating_add(n) <= MAX_PROGRAM_LEN)
+        .ok_or((ParseError::InvalidAlignment, input))?;
+    let rest = skip_extra_field(rest)?;
+    Ok((vec![Statement::Nop; (n - address % n) % n], rest))
+}
+
+// A number written with a radix suffix: `1010b`, `17o` or `0FFh`. The first
+// character must be a digit, so `FFh` stays a label. Leading zeros are
+// allowed since the `h` form needs one before a letter.
+fn parse_suffixed_integer(input: &str) -> Option<(Number, &str)> {
+    parse_one(input, |ch| ch.is_ascii_digit())?;
+    let (token, rest) = parse_while(input, |ch| ch.is_ascii_alphanumeric());
+    let (digits, suffix) = token.split_at(token.len() - 1);
+    let radix = match suffix {
+        "b" | "B" => 2,
+        "o" | "O" => 8,
+        "h" | "H" => 16,
+        _ => return None,
+    };
+    let num = Number::parse_bytes(digits.as_bytes(), radix)?;
+    Some((num, rest))
+}
+
+fn parse_integer(input: &str) -> ParseResult<'_, Number> {
     let (sign, rest) = parse_one(input, |ch| ch == '-').unwrap_or(('+', input));
+    if let Some((mut num, rest)) = parse_suffixed_integer(rest) {
+        if sign == '-' {
+            num = -num
+        }
+        return Ok((num, rest));
+    }
     if let Some((_, rest)) = parse_one(rest, |ch| ch == '0') {
-        if let Some(_) = parse_one(rest, |ch| ch.is_ascii_digit()) {
-            return Err(ParseError::ExtraZero);
+        if parse_one(rest, |ch| ch.is_ascii_digit()).is_some() {
+            return Err((ParseError::ExtraZero, input));
         }
     }
-    if let Some(_) = parse_one(rest, |ch| ch.is_ascii_digit()) {
+    if parse_one(rest, |ch| ch.is_ascii_digit()).is_some() {
         let (num, rest) = parse_while(rest, |ch| ch.is_ascii_digit());
-        let mut num: Number = num.parse().unwrap();
+        let mut num: Number = num
+            .parse()
+            .map_err(|_| (ParseError::ExpectInteger, input))?;
         if sign == '-' {
             num = -num
         }
         Ok((num, rest))
     } else {
-        Err(ParseError::ExpectInteger)
+        Err((ParseError::ExpectInteger, input))
+    }
+}
+
+// The escapes follow Rust: `\xNN` is limited to ASCII and `\u{...}` takes up
+// to six hex digits naming a Unicode scalar value.
+fn parse_escape(input: &str) -> ParseResult<'_, char> {
+    let (ch, rest) = parse_one(input, |_| true).ok_or((ParseError::InvalidEscape, input))?;
+    match ch {
+        'n' => Ok(('\n', rest)),
+        't' => Ok(('\t', rest)),
+        'r' => Ok(('\r', rest)),
+        '0' => Ok(('\0', rest)),
+        '\\' | '\'' | '"' => Ok((ch, rest)),
+        'x' => {
+            let digits = rest.get(..2).ok_or((ParseError::InvalidEscape, input))?;
+            match u8::from_str_radix(digits, 16) {
+                Ok(code) if code <= 0x7f && digits.chars().all(|ch| ch.is_ascii_hexdigit()) => {
+                    Ok((char::from(code), &rest[2..]))
+                }
+                _ => Err((ParseError::InvalidEscape, input)),
+            }
+        }
+        'u' => {
+            let (_, rest) =
+                parse_one(rest, |ch| ch == '{').ok_or((ParseError::InvalidEscape, input))?;
+            let (digits, rest) = parse_while(rest, |ch| ch.is_ascii_hexdigit());
+            let (_, rest) =
+                parse_one(rest, |ch| ch == '}').ok_or((ParseError::InvalidEscape, input))?;
+            if digits.is_empty() || digits.len() > 6 {
+                return Err((ParseError::InvalidEscape, input));
+            }
+            u32::from_str_radix(digits, 16)
+                .ok()
+                .and_then(std::char::from_u32)
+                .map(|ch| (ch, rest))
+                .ok_or((ParseError::InvalidEscape, input))
+        }
+        _ => Err((ParseError::InvalidEscape, input)),
+    }
+}
+
+fn parse_character(input: &str) -> ParseResult<'_, Number> {
+    let (_, rest) = parse_one(input, |ch| ch == '\'').ok_or((ParseError::ExpectValue, input))?;
+    let (ch, rest) = match parse_one(rest, |ch| ch != '\n') {
+        Some(('\'', _)) => return Err((ParseError::EmptyCharLiteral, input)),
+        Some(('\\', rest)) => parse_escape(rest)?,
+        Some(found) => found,
+        None => return Err((ParseError::UnterminatedCharLiteral, input)),
+    };
+    let (_, rest) =
+        parse_one(rest, |ch| ch == '\'').ok_or((ParseError::UnterminatedCharLiteral, input))?;
+    Ok((Number::from(ch as u32), rest))
+}
+
+// A string in a `data` directive stands for the codes of its characters. It
+// takes the same escapes as a character literal and adds no terminator.
+fn parse_string(input: &str) -> ParseResult<'_, Vec<Value>> {
+    let (_, mut rest) = parse_one(input, |ch| ch == '"').ok_or((ParseError::ExpectValue, input))?;
+    let mut values = Vec::new();
+    loop {
+        let (ch, next) = match parse_one(rest, |ch| ch != '\n') {
+            Some(('"', next)) => return Ok((values, next)),
+            Some(('\\', next)) => parse_escape(next)?,
+            Some(found) => found,
+            None => return Err((ParseError::UnterminatedString, input)),
+        };
+        values.push(Value::Immediate(Number::from(ch as u32)));
+        rest = next;
+    }
+}
+
+fn parse_open_bracket(input: &str, grammar: Grammar) -> Option<(char, &str)> {
+    match parse_one(input, |ch| {
+        ch == '[' || (grammar.paren_indirect && ch == '(')
+    }) {
+        Some(('[', rest)) => Some((']', rest)),
+        Some((_, rest)) => Some((')', rest)),
+        None => None,
+    }
+}
+
+// Catches `incr 5], 1`, where the operand itself parsed but a closing bracket
+// with no matching opener follows it.
+fn reject_stray_bracket<T>(parsed: (T, &str), grammar: Grammar) -> ParseResult<'_, T> {
+    let (operand, rest) = parsed;
+    let next = skip_space(rest);
+    match parse_one(next, |ch| {
+        ch == ']' || (grammar.paren_indirect && ch == ')')
+    }) {
+        Some((ch, _)) => Err((ParseError::UnexpectedCloseBracket(ch), next)),
+        None => Ok((operand, rest)),
     }
 }
 
-fn parse_index(input: &str) -> ParseResult<Index> {
-    if let Some((_, rest)) = parse_one(input, |ch| ch == '[') {
+fn parse_index(input: &str, grammar: Grammar) -> ParseResult<'_, Index> {
+    if let Some((close, rest)) = parse_open_bracket(input, grammar) {
+        if let Some((_, rest)) = parse_one(rest, |ch| ch == if close == ']' { '[' } else { '(' }) {
+            let rest = skip_space(rest);
+            let (num, rest) = parse_integer(rest)?;
+            let rest = skip_space(rest);
+            let (_, rest) =
+                parse_one(rest, |ch| ch == close).ok_or((ParseError::UnclosedBracket, rest))?;
+            let (_, rest) =
+                parse_one(rest, |ch| ch == close).ok_or((ParseError::UnclosedBracket, rest))?;
+            return reject_stray_bracket((Index::Pointer(num), rest), grammar);
+        }
         let rest = skip_space(rest);
         let (num, rest) = parse_integer(rest)?;
         let rest = skip_space(rest);
-        if let Some((_, rest)) = parse_one(rest, |ch| ch == ']') {
-            Ok((Index::Indirect(num), rest))
+        if let Some((_, rest)) = parse_one(rest, |ch| ch == close) {
+            reject_stray_bracket((Index::Indirect(num), rest), grammar)
         } else {
-            Err(ParseError::UnclosedBracket)
+            Err((ParseError::UnclosedBracket, rest))
         }
+    } else if parse_identifier(input).is_ok_and(|(ident, _)| ident == "pc") {
+        Err((ParseError::ProgramCounterIndex, input))
+    } else if parse_one(input, |ch| ch.is_ascii_alphabetic()).is_some() {
+        Err((ParseError::ExpectIndex, input))
     } else {
         let (num, rest) = parse_integer(input)?;
-        Ok((Index::Direct(num), rest))
+        reject_stray_bracket((Index::Direct(num), rest), grammar)
     }
 }
 
-fn parse_address(input: &str) -> ParseResult<Address> {
-    if let Some((_, rest)) = parse_one(input, |ch| ch == '[') {
+fn parse_address(input: &str, grammar: Grammar) -> ParseResult<'_, Address> {
+    if let Some((close, rest)) = parse_open_bracket(input, grammar) {
         let rest = skip_space(rest);
         let (num, rest) = parse_integer(rest)?;
         let rest = skip_space(rest);
-        let (_, rest) = parse_one(rest, |ch| ch == ']').ok_or(ParseError::UnclosedBracket)?;
-        Ok((Address::Register(num), rest))
+        let (_, rest) =
+            parse_one(rest, |ch| ch == close).ok_or((ParseError::UnclosedBracket, rest))?;
+        reject_stray_bracket((Address::Register(num), rest), grammar)
     } else if let Ok((num, rest)) = parse_integer(input) {
-        Ok((Address::Immediate(num), rest))
+        reject_stray_bracket((Address::Immediate(num), rest), grammar)
     } else if let Ok((ident, rest)) = parse_identifier(input) {
         if ident == "pc" {
-            Ok((Address::ProgramCounter, rest))
+            reject_stray_bracket((Address::ProgramCounter, rest), grammar)
         } else {
-            Ok((Address::Label(ident), rest))
+            reject_stray_bracket((Address::Label(ident), rest), grammar)
         }
     } else {
-        Err(ParseError::ExpectAddress)
+        Err((ParseError::ExpectAddress, input))
     }
 }
 
-fn parse_value(input: &str) -> ParseResult<Value> {
-    if let Some((_, rest)) = parse_one(input, |ch| ch == '[') {
-        if let Some((_, rest)) = parse_one(rest, |ch| ch == '[') {
+fn parse_value(input: &str, grammar: Grammar) -> ParseResult<'_, Value> {
+    if let Some((close, rest)) = parse_open_bracket(input, grammar) {
+        if let Some((_, rest)) = parse_one(rest, |ch| ch == if close == ']' { '[' } else { '(' }) {
             let rest = skip_space(rest);
             let (num, rest) = parse_integer(rest)?;
             let rest = skip_space(rest);
-            let (_, rest) = parse_one(rest, |ch| ch == ']').ok_or(ParseError::UnclosedBracket)?;
-            let (_, rest) = parse_one(rest, |ch| ch == ']').ok_or(ParseError::UnclosedBracket)?;
-            Ok((Value::Pointer(num), rest))
+            let (_, rest) =
+                parse_one(rest, |ch| ch == close).ok_or((ParseError::UnclosedBracket, rest))?;
+            let (_, rest) =
+                parse_one(rest, |ch| ch == close).ok_or((ParseError::UnclosedBracket, rest))?;
+            reject_stray_bracket((Value::Pointer(num), rest), grammar)
         } else {
             let rest = skip_space(rest);
             let (num, rest) = parse_integer(rest)?;
             let rest = skip_space(rest);
-            let (_, rest) = parse_one(rest, |ch| ch == ']').ok_or(ParseError::UnclosedBracket)?;
-            Ok((Value::Register(num), rest))
+            let (_, rest) =
+                parse_one(rest, |ch| ch == close).ok_or((ParseError::UnclosedBracket, rest))?;
+            reject_stray_bracket((Value::Register(num), rest), grammar)
         }
+    } else if parse_one(input, |ch| ch == '\'').is_some() {
+        let (num, rest) = parse_character(input)?;
+        reject_stray_bracket((Value::Immediate(num), rest), grammar)
     } else if let Ok((num, rest)) = parse_integer(input) {
-        Ok((Value::Immediate(num), rest))
+        reject_stray_bracket((Value::Immediate(num), rest), grammar)
     } else if let Ok((ident, rest)) = parse_identifier(input) {
         if ident == "pc" {
-            Ok((Value::ProgramCounter, rest))
+            reject_stray_bracket((Value::ProgramCounter, rest), grammar)
         } else {
-            Ok((Value::Label(ident), rest))
+            reject_stray_bracket((Value::Label(ident), rest), grammar)
         }
     } else {
-        Err(ParseError::ExpectValue)
+        Err((ParseError::ExpectValue, input))
+    }
+}
+
+fn parse_data_value(input: &str, grammar: Grammar) -> ParseResult<'_, Vec<Value>> {
+    if input.starts_with('"') {
+        return parse_string(input);
+    }
+    match parse_value(input, grammar)? {
+        (value @ Value::Immediate(_), rest) | (value @ Value::Label(_), rest) => {
+            Ok((vec![value], rest))
+        }
+        _ => Err((ParseError::ExpectValue, input)),
     }
 }
 
-fn parse_line(input: &str) -> ParseResult<Line> {
-    let (label, rest) = parse_label(input)?;
+// `data INDEX, v0, v1, ...` seeds registers before the program starts. It
+// generates no instruction, so it takes no label.
+fn parse_data(input: &str, line: usize, grammar: Grammar) -> ParseResult<'_, Data> {
+    let (index, rest) = parse_integer(input)?;
     let rest = skip_space(rest);
-    match rest.chars().next() {
-        Some(';') | Some('\n') => label.map_or_else(
-            || parse_line(skip_comment(rest)),
-            |_| Err(ParseError::LabelOnly),
-        ),
-        Some(_) => {
-            let (command, rest) = parse_command(rest)?;
-            Ok((Line::new(label, command), rest))
+    let (_, mut rest) = parse_one(rest, |ch| ch == ',').ok_or((ParseError::ExpectValue, rest))?;
+    let mut values = Vec::new();
+    loop {
+        let (value, next) = parse_data_value(skip_space(rest), grammar)?;
+        values.extend(value);
+        match parse_one(skip_space(next), |ch| ch == ',') {
+            Some((_, next)) => rest = next,
+            None => {
+                let rest = skip_extra_field(next)?;
+                return Ok((Data::new(index, values, line), rest));
+            }
         }
-        _ => Err(ParseError::EndOfProgram),
     }
 }
 
-fn parse(input: &str) -> std::result::Result<Ast, ParseError> {
-    let mut lines = Vec::new();
+// `NAME equ VALUE` names a number. The name sits in the label field but does
+// not name an address.
+fn parse_constant(input: &str, grammar: Grammar) -> ParseResult<'_, Number> {
+    match parse_value(input, grammar)? {
+        (Value::Immediate(n), rest) => Ok((n, skip_extra_field(rest)?)),
+        _ => Err((ParseError::ExpectInteger, input)),
+    }
+}
+
+// Everything a line can add to the AST besides its instructions.
+#[derive(Default)]
+struct Collected {
+    labels: Vec<Label>,
+    sources: Vec<SourceLine>,
+    comments: Vec<Comment>,
+    data: Vec<Data>,
+    constants: Vec<Constant>,
+}
+
+impl Collected {
+    fn into_ast(self, lines: Vec<Line>) -> Ast {
+        let mut ast = Ast::new(lines, self.comments);
+        ast.set_labels(self.labels);
+        ast.set_sources(self.sources);
+        ast.set_data(self.data);
+        ast.set_constants(self.constants);
+        ast
+    }
+}
+
+fn is_align(input: &str) -> bool {
+    parse_while(input, |ch| ch.is_ascii_alphanumeric()).0 == "align"
+}
+
+fn parse_line<'a>(
+    input: &'a str,
+    line: usize,
+    address: usize,
+    grammar: Grammar,
+    collected: &mut Collected,
+) -> ParseResult<'a, Vec<Line>> {
     let mut input = input;
-    let mut count = 0;
+    let mut line = line;
     loop {
-        match parse_line(input) {
-            Ok((line, rest)) => {
-                lines.push(line);
+        let (label, rest) = parse_label(input)?;
+        let rest = skip_space(rest);
+        let start = rest;
+        let (word, after) = parse_while(rest, |ch| ch.is_ascii_alphanumeric());
+        if word == "data" {
+            if label.is_some() {
+                return Err((ParseError::LabelOnly, input));
+            }
+            let (block, rest) = parse_data(skip_space(after), line, grammar)?;
+            if rest.starts_with(':') {
+                return Err((ParseError::NotAlone("data"), rest));
+            }
+            let (comment, next) = parse_comment(input, rest, line);
+            collected.sources.push(SourceLine {
+                line,
+                label: None,
+                text: written(start, rest),
+                comment,
+            });
+            collected.data.push(block);
+            input = next;
+            line += 1;
+            continue;
+        }
+        if word == "equ" {
+            let name = label.ok_or((ParseError::MissingConstantName, rest))?;
+            let (value, rest) = parse_constant(skip_space(after), grammar)?;
+            if rest.starts_with(':') {
+                return Err((ParseError::NotAlone("equ"), rest));
+            }
+            let (comment, next) = parse_comment(input, rest, line);
+            collected.sources.push(SourceLine {
+                line,
+                label: Some(name.clone()),
+                text: written(start, rest),
+                comment,
+            });
+            collected.constants.push(Constant { name, value, line });
+            input = next;
+            line += 1;
+            continue;
+        }
+        if !is_blank(rest) {
+            let mut statements = Vec::new();
+            // The label names the first instruction that isn't `align`
+            // padding, or the address after the line if there is none.
+            let mut labelled = None;
+            let mut segment = rest;
+            let rest = loop {
+                let (more, next) = parse_repeated(segment, grammar, address + statements.len())?;
+                if labelled.is_none() && !more.is_empty() && !is_align(segment) {
+                    labelled = Some(statements.len());
+                }
+                statements.extend(more);
+                match parse_one(next, |ch| ch == ':') {
+                    Some((_, next)) => segment = skip_space(next),
+                    None => break next,
+                }
+            };
+            let text = written(start, rest);
+            let (comment, rest) = parse_comment(input, rest, line);
+            collected.sources.push(SourceLine {
+                line,
+                label: label.clone(),
+                text,
+                comment,
+            });
+            let labelled = labelled.unwrap_or(statements.len());
+            if let Some(name) = &label {
+                collected.labels.push(Label {
+                    name: name.clone(),
+                    address: address + labelled,
+                    line,
+                });
+            }
+            if statements.is_empty() {
                 input = rest;
+                line += 1;
+                continue;
             }
-            Err(ParseError::EndOfProgram) => break,
-            Err(err) => {
-                println!("{}", count);
-                Err(err)?
+            let mut label = label;
+            let lines: Vec<Line> = statements
+                .into_iter()
+                .enumerate()
+                .map(|(i, statement)| {
+                    let label = if i == labelled { label.take() } else { None };
+                    Line::new(label, statement, line)
+                })
+                .collect();
+            return Ok((lines, rest));
+        } else if label.is_some() {
+            return Err((ParseError::LabelOnly, input));
+        } else if rest.is_empty() {
+            return Err((ParseError::EndOfProgram, rest));
+        }
+        let (comment, next) = parse_comment(input, rest, line);
+        collected.comments.extend(comment);
+        input = next;
+        line += 1;
+    }
+}
+
+#[derive(Debug)]
+pub struct SyntaxError {
+    pub error: ParseError,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SyntaxError {
+    fn new(source: &str, error: ParseError, rest: &str) -> SyntaxError {
+        let (before, _) = source.split_at(source.len() - rest.len());
+        let line_start = before.rfind('\n').map_or(0, |pos| pos + 1);
+        SyntaxError {
+            error,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+impl std::fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.error
+        )
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+/// Why `str::parse::<Program>` failed: the source did not parse, or a label
+/// or operand could not be resolved afterwards.
+#[derive(Debug)]
+pub enum AssembleError {
+    /// The `ParseError` together with the line and column it was found at.
+    Parse(SyntaxError),
+    Compile(CompileError),
+}
+
+impl std::fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AssembleError::Parse(e) => write!(f, "{}", e),
+            AssembleError::Compile(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// Why `compile_file` failed. A source that doesn't assemble comes with the
+/// text that was read, so the error can be shown in context.
+#[derive(Debug)]
+pub enum FileError {
+    Io(std::io::Error),
+    Encoding(std::string::FromUtf8Error),
+    Assemble {
+        source: String,
+        error: AssembleError,
+    },
+}
+
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FileError::Io(e) => write!(f, "{}", e),
+            FileError::Encoding(e) => write!(f, "file is not valid UTF-8: {}", e),
+            FileError::Assemble { error, .. } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for FileError {}
+
+pub struct Statements<'a> {
+    grammar: Grammar,
+    source: &'a str,
+    input: &'a str,
+    line_number: usize,
+    address: usize,
+    pending: std::collections::VecDeque<Line>,
+    collected: Collected,
+    finished: bool,
+}
+
+impl<'a> Iterator for Statements<'a> {
+    type Item = std::result::Result<Line, SyntaxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(line) = self.pending.pop_front() {
+            return Some(Ok(line));
+        }
+        if self.finished {
+            return None;
+        }
+        match parse_line(
+            self.input,
+            self.line_number,
+            self.address,
+            self.grammar,
+            &mut self.collected,
+        ) {
+            Ok((statements, rest)) => {
+                self.line_number = statements[0].line() + 1;
+                self.address += statements.len();
+                self.pending.extend(statements);
+                self.input = rest;
+                self.pending.pop_front().map(Ok)
+            }
+            Err((ParseError::EndOfProgram, _)) => {
+                self.finished = true;
+                None
+            }
+            Err((err, rest)) => {
+                self.finished = true;
+                Some(Err(SyntaxError::new(self.source, err, rest)))
+            }
+        }
+    }
+}
+
+impl Grammar {
+    pub fn statements(self, source: &str) -> Statements<'_> {
+        Statements {
+            grammar: self,
+            source,
+            input: source,
+            line_number: 1,
+            address: 0,
+            pending: std::collections::VecDeque::new(),
+            collected: Collected::default(),
+            finished: false,
+        }
+    }
+
+    /// Parses the whole source without stopping at the first error. A line
+    /// with a syntax error is skipped and parsing resumes on the next one, so
+    /// the result holds every line that parsed. Nothing borrows the source.
+    pub fn parse_ast(self, source: &str) -> (Ast, Vec<SyntaxError>) {
+        let mut lines = Vec::new();
+        let mut collected = Collected::default();
+        let mut errors = Vec::new();
+        let mut input = source;
+        let mut line_number = 1;
+        loop {
+            match parse_line(input, line_number, lines.len(), self, &mut collected) {
+                Ok((statements, rest)) => {
+                    line_number = statements[0].line() + 1;
+                    lines.extend(statements);
+                    input = rest;
+                }
+                Err((ParseError::EndOfProgram, _)) => break,
+                Err((err, rest)) => {
+                    let error = SyntaxError::new(source, err, rest);
+                    line_number = error.line + 1;
+                    errors.push(error);
+                    input = skip_comment(rest);
+                }
             }
         }
-        count += 1;
+        (collected.into_ast(lines), errors)
+    }
+
+    pub fn assemble(self, source: &str) -> std::result::Result<Program, AssembleError> {
+        let ast = parse(source, self).map_err(AssembleError::Parse)?;
+        Program::new(ast).map_err(AssembleError::Compile)
     }
-    Ok(Ast(lines))
+}
+
+pub fn parse_ast(source: &str) -> (Ast, Vec<SyntaxError>) {
+    Grammar::default().parse_ast(source)
+}
+
+pub fn statements(source: &str) -> Statements<'_> {
+    Grammar::default().statements(source)
+}
+
+pub(crate) fn parse(source: &str, grammar: Grammar) -> std::result::Result<Ast, SyntaxError> {
+    let mut lines = Vec::new();
+    let mut statements = grammar.statements(source);
+    for line in statements.by_ref() {
+        lines.push(line?);
+    }
+    Ok(statements.collected.into_ast(lines))
+}
+
+/// Reformats the source in the tab separated layout of the assembler's own
+/// output, keeping every comment.
+pub fn format_source(source: &str) -> std::result::Result<String, SyntaxError> {
+    Ok(parse(source, Grammar::default())?.format())
+}
+
+/// Like `format_source`, with the label column laid out as the options say.
+pub fn format_source_with(
+    source: &str,
+    options: &FormatOptions,
+) -> std::result::Result<String, SyntaxError> {
+    Ok(parse(source, Grammar::default())?.format_with(options))
 }
 
 use std::str::FromStr;
 
 impl FromStr for Program {
-    type Err = String;
+    type Err = AssembleError;
 
-    fn from_str(source: &str) -> std::result::Result<Program, String> {
-        let ast = parse(source);
-        match ast {
-            Ok(ast) => Ok(Program::new(ast).ok_or("Unknown label")?),
-            Err(err) => Err(format!("{:?}", err)),
-        }
+    fn from_str(source: &str) -> std::result::Result<Program, AssembleError> {
+        Grammar::default().assemble(source)
     }
 }
@@ -0,0 +1,78 @@
+use crate::syntax_tree::{Address, Index, Number, Statement, Value};
+use num_traits::{Signed, ToPrimitive};
+
+/// A value operand that needs no evaluation beyond a register read.
+pub(crate) enum Operand {
+    Immediate(Number),
+    Register(usize),
+}
+
+impl Operand {
+    fn compile(value: &Value) -> Option<Operand> {
+        match value {
+            Value::Immediate(n) => Some(Operand::Immediate(n.clone())),
+            Value::Register(n) => n.to_usize().map(Operand::Register),
+            _ => None,
+        }
+    }
+}
+
+/// An instruction prepared once per program so that `run` doesn't evaluate
+/// constant operands on every pass. Only the forms that dominate loops are
+/// prepared: a direct register index, a value that is an immediate or a
+/// register, and an immediate jump target. Everything else is interpreted
+/// from its `Statement`.
+pub(crate) enum Op {
+    Incr {
+        index: usize,
+        value: Operand,
+    },
+    Decr {
+        index: usize,
+        target: usize,
+        value: Operand,
+    },
+    /// `decr` on a negative register with a positive value always jumps.
+    Jump {
+        target: usize,
+    },
+    Save {
+        index: usize,
+        value: Operand,
+    },
+    Interpret,
+}
+
+impl Op {
+    pub(crate) fn compile(statement: &Statement) -> Op {
+        match statement {
+            Statement::Incr(Index::Direct(i), v) => match (i.to_usize(), Operand::compile(v)) {
+                (Some(index), Some(value)) => Op::Incr { index, value },
+                _ => Op::Interpret,
+            },
+            Statement::Decr(Index::Direct(i), Address::Immediate(a), v) => {
+                match (i.to_usize(), a.to_usize(), v) {
+                    (None, Some(target), Value::Immediate(v))
+                        if i.is_negative() && v.is_positive() =>
+                    {
+                        Op::Jump { target }
+                    }
+                    (Some(index), Some(target), v) => match Operand::compile(v) {
+                        Some(value) => Op::Decr {
+                            index,
+                            target,
+                            value,
+                        },
+                        None => Op::Interpret,
+                    },
+                    _ => Op::Interpret,
+                }
+            }
+            Statement::Save(Index::Direct(i), v) => match (i.to_usize(), Operand::compile(v)) {
+                (Some(index), Some(value)) => Op::Save { index, value },
+                _ => Op::Interpret,
+            },
+            _ => Op::Interpret,
+        }
+    }
+}
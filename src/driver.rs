@@ -0,0 +1,57 @@
+use crate::syntax_tree::{Number, Program};
+use crate::vm::{MachineState, Output, RunError};
+
+/// Where `Driver::run_for` stopped.
+#[derive(Debug, PartialEq)]
+pub enum DriveOutcome {
+    /// The program halted with the value of register 0.
+    Halted(Number),
+    /// The budget ran out; the next call continues from here.
+    Yielded,
+}
+
+/// Runs a program in bursts of a bounded number of instructions, so that a
+/// host such as an event loop can interleave other work between them.
+pub struct Driver<'a, 'b, T: Output> {
+    machine: MachineState<'b, T>,
+    program: &'a Program,
+    result: Option<Number>,
+}
+
+impl<'a, 'b, T: Output> Driver<'a, 'b, T> {
+    pub fn new(machine: MachineState<'b, T>, program: &'a Program) -> Driver<'a, 'b, T> {
+        Driver {
+            machine,
+            program,
+            result: None,
+        }
+    }
+
+    /// Executes up to `budget` instructions. Once the program has halted,
+    /// further calls return the same result without running anything.
+    pub fn run_for(&mut self, budget: u64) -> Result<DriveOutcome, RunError> {
+        if let Some(result) = &self.result {
+            return Ok(DriveOutcome::Halted(result.clone()));
+        }
+        for _ in 0..budget {
+            if self.machine.step(self.program)? {
+                let result = self.machine.read_register(0);
+                self.result = Some(result.clone());
+                return Ok(DriveOutcome::Halted(result));
+            }
+        }
+        Ok(DriveOutcome::Yielded)
+    }
+
+    pub fn machine(&self) -> &MachineState<'b, T> {
+        &self.machine
+    }
+
+    pub fn machine_mut(&mut self) -> &mut MachineState<'b, T> {
+        &mut self.machine
+    }
+
+    pub fn into_machine(self) -> MachineState<'b, T> {
+        self.machine
+    }
+}
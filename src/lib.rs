@@ -1,58 +1,2494 @@
-pub use syntax_tree::Program;
-pub use vm::MachineState;
+pub use bytecode::BytecodeError;
+pub use compiler::{
+    format_source, format_source_with, mnemonics, parse_ast, statements, AssembleError, FileError,
+    Grammar, MnemonicInfo, OperandKind, ParseError, Statements, SyntaxError,
+};
+pub use driver::{DriveOutcome, Driver};
+pub use lint::{compile, Diagnostic, Lint, Severity};
+pub use sandbox::{run_sandboxed, Limits, SandboxError};
+pub use syntax_tree::{
+    Address, Ast, Comment, CompileError, Constant, Data, FormatOptions, Index, Label, Line,
+    Program, SourceLine, Statement, Value,
+};
+pub use vm::{
+    BranchCount, HaltReason, MachineBuilder, MachineState, MemoryMode, NullOutput, Output,
+    RunError, RunState, RunStats, Syscall, Throughput,
+};
 
+mod bytecode;
+mod canonical;
 mod compiler;
+mod dispatch;
+mod driver;
+mod lint;
+mod sandbox;
 mod syntax_tree;
 mod vm;
 
+pub fn run_to_string(src: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let program: Program = src.parse()?;
+    let mut buffer = Vec::new();
+    let mut machine = MachineState::new(&mut buffer);
+    machine.run(&program)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+/// Runs the program once for every input, each time on a fresh machine
+/// reading that input, and collects what each run printed.
+pub fn run_batch(program: &Program, inputs: &[Vec<u8>]) -> Vec<Result<String, RunError>> {
+    inputs
+        .iter()
+        .map(|input| {
+            let mut reader = &input[..];
+            let mut buffer = Vec::new();
+            let mut machine = MachineState::new(&mut buffer);
+            machine.set_input(&mut reader);
+            machine.run(program)?;
+            drop(machine);
+            Ok(String::from_utf8_lossy(&buffer).into_owned())
+        })
+        .collect()
+}
+
+/// Reads a source file, rejecting contents that are not valid UTF-8.
+pub fn read_source<P: AsRef<std::path::Path>>(path: P) -> Result<String, FileError> {
+    let bytes = std::fs::read(path).map_err(FileError::Io)?;
+    String::from_utf8(bytes).map_err(FileError::Encoding)
+}
+
+/// Reads and assembles a source file.
+pub fn compile_file<P: AsRef<std::path::Path>>(path: P) -> Result<Program, FileError> {
+    let source = read_source(path)?;
+    source
+        .parse()
+        .map_err(|error| FileError::Assemble { source, error })
+}
+
+/// Locks stdout behind a `BufWriter`. The machine flushes its output when it
+/// halts, so nothing is lost on a normal exit.
+pub fn buffered_stdout() -> std::io::BufWriter<std::io::StdoutLock<'static>> {
+    std::io::BufWriter::new(std::io::stdout().lock())
+}
+
 #[cfg(test)]
 mod tests {
     extern crate num_bigint;
     extern crate num_traits;
-    use super::vm;
+    use super::{run_to_string, vm};
     use num_bigint::BigInt;
     use std::str::FromStr;
 
+    // Runs the program with its output captured and checks both the value
+    // it returns and everything it prints.
+    fn assert_program(
+        src: &str,
+        expected_return: BigInt,
+        expected_output: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = src.parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        let result = machine.run(&program)?;
+        drop(machine);
+        assert_eq!(result, expected_return);
+        assert_eq!(String::from_utf8(buffer)?, expected_output);
+        Ok(())
+    }
+
+    // factorial, square, fibonacci and labelvalue leave their answer in
+    // register 0 and print nothing, so only fizzbuzz and putc check printed
+    // output.
     #[test]
     fn factorial_test() -> Result<(), Box<dyn std::error::Error>> {
+        assert_program(
+            include_str!("../testcase/factorial.asm"),
+            BigInt::from(120),
+            "",
+        )
+    }
+
+    #[test]
+    fn square_test() -> Result<(), Box<dyn std::error::Error>> {
+        assert_program(include_str!("../testcase/square.asm"), BigInt::from(55), "")
+    }
+
+    #[test]
+    fn fibonacci_test() -> Result<(), Box<dyn std::error::Error>> {
+        assert_program(
+            include_str!("../testcase/fibonacci.asm"),
+            BigInt::from_str("354224848179261915075")?,
+            "",
+        )
+    }
+
+    #[test]
+    fn labelvalue_test() -> Result<(), Box<dyn std::error::Error>> {
+        assert_program(
+            include_str!("../testcase/labelvalue.asm"),
+            BigInt::from(10),
+            "",
+        )
+    }
+
+    #[test]
+    fn fizzbuzz_test() -> Result<(), Box<dyn std::error::Error>> {
+        let expected: String = (0..=100)
+            .map(|n| {
+                format!(
+                    "{}{}{}\n",
+                    n,
+                    if n % 3 == 0 { "Fizz" } else { "" },
+                    if n % 5 == 0 { "Buzz" } else { "" }
+                )
+            })
+            .collect();
+        assert_program(
+            include_str!("../testcase/fizzbuzz.asm"),
+            BigInt::from(0),
+            &expected,
+        )
+    }
+
+    #[test]
+    fn putc_test() -> Result<(), Box<dyn std::error::Error>> {
+        assert_program(
+            include_str!("../testcase/putc.asm"),
+            BigInt::from(0),
+            "Hi!\n\u{3042}\0\n",
+        )
+    }
+
+    #[test]
+    fn profile_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = include_str!("../testcase/fizzbuzz.asm").parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        let (_, stats) = machine.run_with_stats(&program)?;
+        assert_eq!(stats.count("putn"), 101);
+        assert!(stats.count("incr") > stats.count("putn"));
+        assert!(stats.count("decr") > stats.count("putn"));
+        assert_eq!(stats.count("halt"), 1);
+        assert_eq!(stats.total(), stats.iter().map(|(_, count)| count).sum());
+        let table = stats.to_string();
+        assert!(table.starts_with("mnemonic       count\n"));
+        assert!(table.contains("\nputn             101\n"));
+        assert!(table.ends_with(&format!("total{:>15}\n", stats.total())));
+        Ok(())
+    }
+
+    #[test]
+    fn run_to_string_test() -> Result<(), Box<dyn std::error::Error>> {
+        let output = run_to_string(
+            "        save 1, 42
+        putc 65
+        putn [1]
+        putc 10
+        halt
+",
+        )?;
+        assert_eq!(output, "A42\n");
+        Ok(())
+    }
+
+    #[test]
+    fn negative_jump_target_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = "        save 1, -3
+        decr 2, [1]
+        halt
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        let error = machine.run(&program).err().ok_or("expected an error")?;
+        match error.cause() {
+            vm::RunError::InvalidJumpTarget(n) => assert_eq!(*n, BigInt::from(-3)),
+            _ => panic!("expected InvalidJumpTarget"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn error_line_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = "; invalid character code
+        save 1, -1
+
+        putc [1]
+        halt
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        let error = machine.run(&program).err().ok_or("expected an error")?;
+        assert_eq!(error.line(), Some(4));
+        assert!(matches!(error.cause(), vm::RunError::InvalidOutputChar(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn puts_test() -> Result<(), Box<dyn std::error::Error>> {
+        let output = run_to_string(
+            "        save 10, 72
+        save 11, 73
+        save 12, 0
+        puts 10
+        halt
+",
+        )?;
+        assert_eq!(output, "HI");
+        Ok(())
+    }
+
+    #[test]
+    fn gets_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = "        gets 10
+        putn [10]
+        putc 44
+        putn [11]
+        putc 44
+        putn [12]
+        halt
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        let mut input = &b"HI\n"[..];
+        machine.set_input(&mut input);
+        machine.run(&program)?;
+        assert_eq!(String::from_utf8(buffer)?, "72,73,0");
+        Ok(())
+    }
+
+    #[test]
+    fn self_loop_lint_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        save 0, 1
+loop    decr 0, loop, 0
+        halt
+"
+        .parse()?;
+        let diagnostics = program.lint();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].kind, super::Lint::SelfLoop);
+        assert_eq!(diagnostics[0].address, Some(1));
+        let program: super::Program = include_str!("../testcase/factorial.asm").parse()?;
+        assert!(program.lint().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn far_register_allocation_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = "        save 99999, 7
+        save 0, [99999]
+        halt
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        assert_eq!(machine.run(&program)?, BigInt::from(7));
+        assert!(machine.allocated_registers() < 100);
+        Ok(())
+    }
+
+    fn run_program(src: &str) -> Result<BigInt, Box<dyn std::error::Error>> {
+        let program = src.parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        Ok(machine.run(&program)?)
+    }
+
+    #[test]
+    fn pointer_value_test() -> Result<(), Box<dyn std::error::Error>> {
+        let incr = "        save 2, 5
+        save 5, 9
+        incr 0, [[2]]
+        halt
+";
+        assert_eq!(run_program(incr)?, BigInt::from(9));
+        let decr = "        save 0, 10
+        save 2, 5
+        save 5, 4
+        decr 0, end, [[2]]
+end     halt
+";
+        assert_eq!(run_program(decr)?, BigInt::from(6));
+        let save = "        save 2, 5
+        save 5, 3
+        save 0, [[2]]
+        halt
+";
+        assert_eq!(run_program(save)?, BigInt::from(3));
+        let out_of_range = "        save 2, 200000
+        save 3, -1
+        save 0, 4
+        incr 0, [[2]]
+        incr 0, [[3]]
+        halt
+";
+        assert_eq!(run_program(out_of_range)?, BigInt::from(4));
+        Ok(())
+    }
+
+    #[test]
+    fn incr_negative_test() -> Result<(), Box<dyn std::error::Error>> {
+        let negative_index = "        save 0, 3
+        incr -1, 5
+        halt
+";
+        assert_eq!(run_program(negative_index)?, BigInt::from(3));
+        let negative_value = "        save 0, 3
+        incr 0, -5
+        halt
+";
+        assert_eq!(run_program(negative_value)?, BigInt::from(-2));
+        Ok(())
+    }
+
+    #[test]
+    fn watch_test() -> Result<(), Box<dyn std::error::Error>> {
         let program = include_str!("../testcase/factorial.asm").parse()?;
-        let stdout = std::io::stdout();
-        let mut handle = stdout.lock();
-        let mut machine = vm::MachineState::new(&mut handle);
-        assert_eq!(machine.run(&program), BigInt::from(120));
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        let watched = [0].iter().cloned().collect();
+        let mut updates = Vec::new();
+        loop {
+            match machine.run_with_watch(&program, &watched)? {
+                vm::RunState::Paused {
+                    index, old, new, ..
+                } => {
+                    assert_eq!(index, 0);
+                    updates.push((old, new));
+                }
+                vm::RunState::Halted(result) => {
+                    assert_eq!(result, BigInt::from(120));
+                    break;
+                }
+                _ => panic!("unexpected state"),
+            }
+        }
+        let expected: Vec<(BigInt, BigInt)> = [(0, 1), (1, 5), (5, 20), (20, 60), (60, 120)]
+            .iter()
+            .map(|&(old, new)| (BigInt::from(old), BigInt::from(new)))
+            .collect();
+        assert_eq!(updates, expected);
         Ok(())
     }
 
     #[test]
-    fn square_test() -> Result<(), Box<dyn std::error::Error>> {
+    fn run_until_test() -> Result<(), Box<dyn std::error::Error>> {
         let program = include_str!("../testcase/square.asm").parse()?;
-        let stdout = std::io::stdout();
-        let mut handle = stdout.lock();
-        let mut machine = vm::MachineState::new(&mut handle);
-        assert_eq!(machine.run(&program), BigInt::from(55));
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        let last_iteration = |machine: &vm::MachineState<Vec<u8>>| {
+            *machine.program_counter() == BigInt::from(3)
+                && machine.read_register(1) == BigInt::from(1)
+        };
+        let mut stops = 0;
+        loop {
+            match machine.run_until(&program, &last_iteration)? {
+                vm::RunState::Stopped(pc) => {
+                    assert_eq!(pc, 3);
+                    stops += 1;
+                    machine.step(&program)?;
+                }
+                vm::RunState::Halted(result) => {
+                    assert_eq!(result, BigInt::from(55));
+                    break;
+                }
+                _ => panic!("unexpected state"),
+            }
+        }
+        assert_eq!(stops, 1);
         Ok(())
     }
 
     #[test]
-    fn fibonacci_test() -> Result<(), Box<dyn std::error::Error>> {
-        let program = include_str!("../testcase/fibonacci.asm").parse()?;
-        let stdout = std::io::stdout();
-        let mut handle = stdout.lock();
-        let mut machine = vm::MachineState::new(&mut handle);
+    fn invalid_putc_immediate_test() {
+        let result = "        putc 55296
+        halt
+"
+        .parse::<super::Program>();
+        match result {
+            Err(super::AssembleError::Compile(super::CompileError::InvalidCharacter(n, line))) => {
+                assert_eq!(n, BigInt::from(55296));
+                assert_eq!(line, 1);
+            }
+            _ => panic!("expected InvalidCharacter"),
+        }
+        assert!("        putc 1114112\n        halt\n"
+            .parse::<super::Program>()
+            .is_err());
+    }
+
+    #[test]
+    fn multiple_statements_per_line_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        save 0, 1 : incr 0, 2 : decr -1, done
+        incr 0, 100
+done    halt
+"
+        .parse()?;
+        assert_eq!(program.len(), 5);
         assert_eq!(
-            machine.run(&program),
-            BigInt::from_str("354224848179261915075")?
+            program.to_string(),
+            "save 0, 1\nincr 0, 2\ndecr -1, 4, 1\nincr 0, 100\nhalt\n"
         );
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        assert_eq!(machine.run(&program)?, BigInt::from(3));
         Ok(())
     }
 
     #[test]
-    fn labelvalue_test() -> Result<(), Box<dyn std::error::Error>> {
-        let program = include_str!("../testcase/labelvalue.asm").parse()?;
-        let stdout = std::io::stdout();
-        let mut handle = stdout.lock();
-        let mut machine = vm::MachineState::new(&mut handle);
-        assert_eq!(machine.run(&program), BigInt::from_str("10")?);
+    fn statements_iterator_test() {
+        let source = "; header
+        save 0, 1
+loop    incr 0 : incr 0
+
+        halt
+        bogus 1
+        halt
+";
+        let lines: Vec<_> = super::statements(source).collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[..4].iter().all(|line| line.is_ok()));
+        assert_eq!(
+            lines[1].as_ref().ok().and_then(|line| line.label()),
+            Some("loop")
+        );
+        match lines[4] {
+            Err(ref e) => assert_eq!(e.line, 6),
+            Ok(_) => panic!("expected a syntax error"),
+        }
+    }
+
+    #[test]
+    fn diagnostics_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        save 0, 1
+        decr -1, end
+        incr 0
+end     incr 0
+"
+        .parse()?;
+        let diagnostics = program.lint();
+        let kinds: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.kind, d.address, d.line))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (super::Lint::Unreachable, Some(2), Some(3)),
+                (super::Lint::FallOffEnd, Some(3), Some(4)),
+                (super::Lint::NoReachableHalt, None, None),
+            ]
+        );
+        assert!(diagnostics
+            .iter()
+            .all(|d| d.severity == super::Severity::Warning));
+        Ok(())
+    }
+
+    #[test]
+    fn blank_lines_test() -> Result<(), Box<dyn std::error::Error>> {
+        let source = "
+        save 0, 1
+   \t
+        incr 0   ; trailing comment
+\t  ; whitespace before comment
+\r
+        incr 0\r
+  \t \r
+        halt
+\t
+";
+        let program: super::Program = source.parse()?;
+        assert_eq!(program.len(), 4);
+        let lines: Vec<usize> = (0..program.len())
+            .filter_map(|pc| program.line(pc))
+            .collect();
+        assert_eq!(lines, vec![2, 4, 7, 9]);
+        assert_eq!(run_program(source)?, BigInt::from(3));
+        assert!("loop".parse::<super::Program>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn puth_test() -> Result<(), Box<dyn std::error::Error>> {
+        let output = run_to_string(
+            "        puth 255
+        putc 32
+        putn 255
+        putc 32
+        puth -255
+        halt
+",
+        )?;
+        assert_eq!(output, "ff 255 -ff");
+        Ok(())
+    }
+
+    #[test]
+    fn mnemonic_metadata_test() {
+        use super::OperandKind::{Address, Index, Integer, Value};
+        let metadata: Vec<_> = super::mnemonics()
+            .iter()
+            .map(|info| {
+                (
+                    info.name,
+                    info.required_operands,
+                    info.operand_count,
+                    info.operand_kinds,
+                )
+            })
+            .collect();
+        let expected: Vec<(&str, usize, usize, &[super::OperandKind])> = vec![
+            ("incr", 1, 2, &[Index, Value]),
+            ("decr", 2, 3, &[Index, Address, Value]),
+            ("decrb", 3, 3, &[Index, Index, Value]),
+            ("save", 2, 2, &[Index, Value]),
+            ("savepc", 1, 1, &[Index]),
+            ("loadcode", 2, 2, &[Index, Address]),
+            ("putc", 1, 1, &[Value]),
+            ("putn", 1, 2, &[Value, Value]),
+            ("puth", 1, 1, &[Value]),
+            ("puts", 1, 1, &[Index]),
+            ("gets", 1, 1, &[Index]),
+            ("echo", 0, 0, &[]),
+            ("halt", 0, 0, &[]),
+            ("syscall", 1, 1, &[Integer]),
+            ("nop", 0, 0, &[]),
+            ("call", 1, 1, &[Address]),
+            ("ret", 0, 0, &[]),
+        ];
+        assert_eq!(metadata, expected);
+    }
+
+    #[test]
+    fn paren_indirect_test() -> Result<(), Box<dyn std::error::Error>> {
+        let grammar = super::Grammar {
+            paren_indirect: true,
+            ..Default::default()
+        };
+        let run = |src: &str| -> Result<BigInt, Box<dyn std::error::Error>> {
+            let program = grammar.assemble(src)?;
+            let mut buffer = Vec::new();
+            let mut machine = vm::MachineState::new(&mut buffer);
+            Ok(machine.run(&program)?)
+        };
+        let brackets = "        save 0, 3
+        save 3, 4
+        incr [0], 1
+        save 0, [[0]]
+        halt
+";
+        let parens = "        save 0, 3
+        save 3, 4
+        incr (0), 1
+        save 0, ((0))
+        halt
+";
+        assert_eq!(run(parens)?, run(brackets)?);
+        assert_eq!(run(parens)?, BigInt::from(5));
+        assert!(parens.parse::<super::Program>().is_err());
+        assert!(grammar.assemble("        incr (0], 1\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn throughput_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = include_str!("../testcase/factorial.asm").parse()?;
+        let mut buffer = Vec::new();
+        let (_, stats) = vm::MachineState::new(&mut buffer).run_with_stats(&program)?;
+        let mut buffer = Vec::new();
+        let (result, throughput) =
+            vm::MachineState::new(&mut buffer).run_with_throughput(&program)?;
+        assert_eq!(result, BigInt::from(120));
+        assert_eq!(throughput.instructions, stats.total());
+        assert!(throughput.per_second() >= 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn custom_output_test() -> Result<(), Box<dyn std::error::Error>> {
+        use super::Output;
+        type Number = BigInt;
+
+        #[derive(Debug, PartialEq)]
+        enum Event {
+            Char(char),
+            Number(Number),
+        }
+
+        struct Recorder(Vec<Event>);
+
+        impl Output for Recorder {
+            fn write_char(&mut self, ch: char) -> std::io::Result<()> {
+                self.0.push(Event::Char(ch));
+                Ok(())
+            }
+
+            fn write_number(&mut self, n: &Number) -> std::io::Result<()> {
+                self.0.push(Event::Number(n.clone()));
+                Ok(())
+            }
+        }
+
+        let program = "        putc 65
+        putn 42
+        puth 255
+        halt
+"
+        .parse()?;
+        let mut recorder = Recorder(Vec::new());
+        vm::MachineState::new(&mut recorder).run(&program)?;
+        assert_eq!(
+            recorder.0,
+            vec![
+                Event::Char('A'),
+                Event::Number(BigInt::from(42)),
+                Event::Char('f'),
+                Event::Char('f'),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn syscall_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = "        save 0, 1
+        syscall 7
+        halt
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        machine.register_syscall(BigInt::from(7), |machine, _| {
+            machine.write_register(0, BigInt::from(42))
+        });
+        assert_eq!(machine.run(&program)?, BigInt::from(42));
+
+        let program = "        syscall 8
+        halt
+"
+        .parse()?;
+        let mut machine = vm::MachineState::new(&mut buffer);
+        match machine.run(&program) {
+            Err(e) => assert!(matches!(e.cause(), vm::RunError::UnknownSyscall(_))),
+            Ok(_) => panic!("expected an unknown syscall error"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sandbox_test() {
+        use super::{run_sandboxed, Limits, SandboxError};
+        use std::time::Duration;
+        let limits = Limits {
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let looping = "loop    decr -1, loop
+        halt
+";
+        assert!(matches!(
+            run_sandboxed(looping, limits),
+            Err(SandboxError::Timeout)
+        ));
+        let limits = Limits {
+            max_steps: Some(10),
+            ..limits
+        };
+        assert!(matches!(
+            run_sandboxed(looping, limits),
+            Err(SandboxError::StepLimit)
+        ));
+        assert_eq!(
+            run_sandboxed("        putc 65\n        halt\n", limits).unwrap(),
+            "A"
+        );
+        let printing = "loop    putc 'a'\n        decr -1, loop\n";
+        let limits = Limits {
+            max_output: Some(5),
+            ..Default::default()
+        };
+        assert!(matches!(
+            run_sandboxed(printing, limits),
+            Err(SandboxError::OutputLimit)
+        ));
+        assert!(matches!(
+            run_sandboxed("        jump 0\n", limits),
+            Err(SandboxError::Assemble(_))
+        ));
+
+        // Writing a register allocates every register below it.
+        let limits = Limits {
+            max_registers: Some(100),
+            ..Default::default()
+        };
+        assert!(run_sandboxed("        save 99, 1\n        halt\n", limits).is_ok());
+        assert!(matches!(
+            run_sandboxed("        save 100, 1\n        halt\n", limits),
+            Err(SandboxError::MemoryLimit)
+        ));
+        let spreading = "        save 1, 2000
+loop    incr [1], 1
+        incr 1, 1
+        decr -1, loop
+";
+        assert!(matches!(
+            run_sandboxed(spreading, limits),
+            Err(SandboxError::MemoryLimit)
+        ));
+    }
+
+    #[test]
+    fn branch_histogram_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = include_str!("../testcase/square.asm").parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        machine.run(&program)?;
+        let histogram = machine.branch_histogram();
+        assert_eq!(
+            histogram[&3],
+            super::BranchCount {
+                taken: 1,
+                fallthrough: 5
+            }
+        );
+        assert_eq!(histogram[&10].taken, 5);
+        assert!(!histogram.contains_key(&4));
+        Ok(())
+    }
+
+    #[test]
+    fn label_as_index_test() {
+        let error = match "loop    incr loop, 1\n        halt\n".parse::<super::Program>() {
+            Err(super::AssembleError::Parse(e)) => e,
+            _ => panic!("expected a syntax error"),
+        };
+        assert!(matches!(error.error, super::ParseError::ExpectIndex));
+        assert_eq!(error.column, 14);
+        assert!(error
+            .to_string()
+            .contains("a label or pc can not be used as the index operand"));
+        assert!("        save pc, 1\n        halt\n"
+            .parse::<super::Program>()
+            .is_err());
+    }
+
+    #[test]
+    fn machine_debug_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = include_str!("../testcase/square.asm").parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        machine.run(&program)?;
+        let debug = format!("{:?}", machine);
+        assert!(debug.starts_with("MachineState { program_counter: 11, registers: {0: 55, "));
+        assert!(!debug.contains("1: 0"));
+        Ok(())
+    }
+
+    #[test]
+    fn slash_comment_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = "// note
+        incr 0, 1 // bump
+loop    decr 0, end// tail
+        decr -1, loop
+end     halt //
+";
+        assert_eq!(run_program(program)?, BigInt::from(0));
+        assert!(run_program("        incr 0, 1 / bump\n        halt\n").is_err());
+        assert!(run_program("/ note\n        halt\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn buffered_output_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = "        save 1, 5000
+loop    decr 1, end
+        putc 97
+        decr -1, loop
+end     halt
+"
+        .parse()?;
+        let mut writer = std::io::BufWriter::new(Vec::new());
+        vm::MachineState::new(&mut writer).run(&program)?;
+        assert_eq!(writer.get_ref().len(), 5000);
+        assert!(writer.get_ref().iter().all(|&ch| ch == b'a'));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_operand_test() {
+        let message = |src: &str| match src.parse::<super::Program>() {
+            Err(super::AssembleError::Parse(e)) => e.error.to_string(),
+            _ => panic!("expected a syntax error"),
+        };
+        assert_eq!(
+            message("        decr 0\n        halt\n"),
+            "decr expects INDEX, ADDRESS[, VALUE] but found only 1 operand"
+        );
+        assert_eq!(
+            message("        save 0\n        halt\n"),
+            "save expects INDEX, VALUE but found only 1 operand"
+        );
+    }
+
+    #[test]
+    fn default_value_test() -> Result<(), Box<dyn std::error::Error>> {
+        let implicit = "        save 1, 3
+        save 0, 1
+        incr [0]
+        decr [0], end
+        decr [0], end
+        save 2, 9
+end     save 0, [1]
+        halt
+";
+        let explicit = "        save 1, 3
+        save 0, 1
+        incr [0], 1
+        decr [0], end, 1
+        decr [0], end, 1
+        save 2, 9
+end     save 0, [1]
+        halt
+";
+        assert_eq!(run_program(implicit)?, run_program(explicit)?);
+        assert_eq!(run_program(implicit)?, BigInt::from(2));
+        assert!(run_program("        incr 0,\n        halt\n").is_err());
+        assert!(run_program("        decr 0, 0,\n        halt\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn max_static_register_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        save 42, 1
+        incr 0, [3]
+        halt
+"
+        .parse()?;
+        assert_eq!(program.max_static_register(), Some(42));
+        let program: super::Program = "        save 0, [[1]]
+        halt
+"
+        .parse()?;
+        assert_eq!(program.max_static_register(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn negative_register_test() {
+        for src in &[
+            "        save [-1], 5\n        halt\n",
+            "        incr [-1], 1\n        halt\n",
+            "        save 0, [[-2]]\n        halt\n",
+            "        decrb 0, [-3], 1\n        halt\n",
+            "        putn 7, [-4]\n        halt\n",
+            "        decr -1, [-5]\n        halt\n",
+        ] {
+            match src.parse::<super::Program>() {
+                Err(super::AssembleError::Compile(super::CompileError::NegativeRegister(_, 1))) => {
+                }
+                _ => panic!("expected a negative register error for {:?}", src),
+            }
+        }
+        assert_eq!(
+            run_program("        incr -1, 1\n        halt\n").unwrap(),
+            BigInt::from(0)
+        );
+    }
+
+    #[test]
+    fn unused_label_lint_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "start   save 1, 2
+loop    decr 1, end
+        decr -1, loop
+end     halt
+"
+        .parse()?;
+        let diagnostics = program.lint();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].kind, super::Lint::UnusedLabel);
+        assert_eq!(diagnostics[0].line, Some(1));
+        assert_eq!(
+            diagnostics[0].message,
+            "Label start is defined but never used"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn program_len_test() -> Result<(), Box<dyn std::error::Error>> {
+        let source = include_str!("../testcase/factorial.asm");
+        let program: super::Program = source.parse()?;
+        let instructions = source
+            .lines()
+            .filter(|line| !line.trim_start().is_empty() && !line.trim_start().starts_with(';'))
+            .count();
+        assert_eq!(program.len(), instructions);
+        assert_eq!(program.len(), 20);
+        assert!(!program.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn tab_round_trip_test() -> Result<(), Box<dyn std::error::Error>> {
+        use super::compiler::{parse, Grammar};
+        for source in &[
+            include_str!("../testcase/factorial.asm"),
+            include_str!("../testcase/square.asm"),
+            include_str!("../testcase/labelvalue.asm"),
+            "loop\tdecr 1, end\n\tdecr -1, loop : putc 97\nend\thalt\n",
+        ] {
+            let first = parse(source, Grammar::default())?.to_string();
+            let second = parse(&first, Grammar::default())?.to_string();
+            assert_eq!(first, second);
+            let program: super::Program = source.parse()?;
+            let reparsed: super::Program = first.parse()?;
+            assert_eq!(program.to_string(), reparsed.to_string());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn times_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "start   times 3 nop
+        times 0 incr 0
+        times 2 incr 0, 2 : halt
+"
+        .parse()?;
+        assert_eq!(
+            program.to_string(),
+            "nop\nnop\nnop\nincr 0, 2\nincr 0, 2\nhalt\n"
+        );
+        assert_eq!(program.line(5), Some(3));
+        assert!(program.reachable_instructions()[5]);
+        let mut buffer = Vec::new();
+        assert_eq!(
+            vm::MachineState::new(&mut buffer).run(&program)?,
+            BigInt::from(4)
+        );
+        let program: super::Program = "        decr -1, end
+        times 3 nop
+end     halt
+"
+        .parse()?;
+        assert!(matches!(program[4], super::syntax_tree::Statement::Halt));
+        assert!(run_program("        times -1 nop\n        halt\n").is_err());
+        // A count that would outgrow the program is rejected before
+        // anything is allocated.
+        for src in &[
+            "        times 100000000000 nop\n",
+            "        nop\n        times 100000 nop\n",
+        ] {
+            assert!(matches!(
+                super::statements(src).find_map(Result::err),
+                Some(super::SyntaxError {
+                    error: super::ParseError::InvalidRepeatCount,
+                    ..
+                })
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn jump_targets_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = include_str!("../testcase/fibonacci.asm").parse()?;
+        assert_eq!(program.jump_targets(), vec![4, 8, 11, 13]);
+        let program: super::Program = "        call double
+        call double
+        decr 0, end
+end     halt
+double  incr 1, 2
+        call [3]
+        ret
+"
+        .parse()?;
+        assert_eq!(program.jump_targets(), vec![3, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn set_output_test() -> Result<(), Box<dyn std::error::Error>> {
+        let first: super::Program = "        putc 65\n        halt\n".parse()?;
+        let second: super::Program = "        putc 66\n        halt\n".parse()?;
+        let mut a = Vec::new();
+        let mut b = Vec::new();
+        {
+            let mut machine = vm::MachineState::new(&mut a);
+            machine.run(&first)?;
+            machine.set_output(&mut b);
+            machine.reset();
+            machine.run(&second)?;
+        }
+        assert_eq!(a, b"A");
+        assert_eq!(b, b"B");
+        Ok(())
+    }
+
+    #[test]
+    fn halt_operand_test() {
+        let message = |src: &str| match src.parse::<super::Program>() {
+            Err(super::AssembleError::Parse(e)) => (e.error.to_string(), e.column),
+            _ => panic!("expected a syntax error"),
+        };
+        assert_eq!(
+            message("        halt 5\n"),
+            ("halt does not take operands".to_string(), 14)
+        );
+        assert_eq!(
+            message("        nop 1\n        halt\n").0,
+            "nop does not take operands"
+        );
+    }
+
+    #[test]
+    fn digit_grouping_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = "        putn 1000000
+        putc 32
+        putn -12345
+        putc 32
+        putn 999
+        putc 32
+        puth 16777215
+        halt
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        machine.set_digit_grouping(Some(','));
+        machine.run(&program)?;
+        assert_eq!(String::from_utf8(buffer)?, "1,000,000 -12,345 999 fff,fff");
+        Ok(())
+    }
+
+    #[test]
+    fn format_comments_test() -> Result<(), Box<dyn std::error::Error>> {
+        let source = "; header comment
+        save 1, 5  ; five
+    // indented note
+loop    decr 1, end // count down
+        incr 0 : decr -1, loop ; two statements
+end     halt
+; trailer
+";
+        let formatted = super::format_source(source)?;
+        assert_eq!(
+            formatted,
+            "; header comment
+\tsave 1, 5\t; five
+\t// indented note
+loop\tdecr 1, end\t// count down
+\tincr 0 : decr -1, loop\t; two statements
+end\thalt
+; trailer
+"
+        );
+        assert_eq!(super::format_source(&formatted)?, formatted);
+        let (ast, errors) = super::parse_ast(source);
+        assert!(errors.is_empty());
+        let comments: Vec<_> = ast
+            .sources()
+            .iter()
+            .filter_map(|source| source.comment.as_ref())
+            .map(|comment| (comment.line, comment.column, &comment.text[..]))
+            .collect();
+        assert_eq!(
+            comments,
+            vec![
+                (2, 20, "; five"),
+                (4, 21, "// count down"),
+                (5, 32, "; two statements")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn strict_grammar_test() {
+        let strict = super::Grammar {
+            strict: true,
+            ..Default::default()
+        };
+        let source = "        save 0, 1
+        incr [0]
+        halt
+";
+        assert!(super::Grammar::default().assemble(source).is_ok());
+        match strict.assemble(source) {
+            Err(super::AssembleError::Parse(e)) => {
+                assert!(matches!(
+                    e.error,
+                    super::ParseError::ImplicitOperand("incr")
+                ));
+                assert_eq!(e.line, 2);
+            }
+            _ => panic!("expected a syntax error"),
+        }
+        assert!(strict
+            .assemble("        save 0, 1\n        incr [0], 1\n        halt\n")
+            .is_ok());
+        assert!(strict
+            .assemble("loop    decr 0, loop\n        halt\n")
+            .is_err());
+    }
+
+    #[test]
+    fn owned_parse_test() {
+        let source = String::from(
+            "; counter
+        save 0, 1
+        jump 3
+        incr 0, ]
+        halt
+",
+        );
+        let (ast, errors) = super::parse_ast(&source);
+        drop(source);
+        assert_eq!(ast.len(), 2);
+        assert_eq!(ast[1].line(), 5);
+        assert_eq!(ast.comments()[0].text, "; counter");
+        assert_eq!(
+            errors.iter().map(|e| e.line).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert!(matches!(
+            errors[0].error,
+            super::ParseError::UnknownMnemonic
+        ));
+    }
+
+    #[test]
+    fn register_limit_test() {
+        for src in &["        gets -1\n", "        save -1, 5\n"] {
+            match src.parse::<super::Program>() {
+                Err(super::AssembleError::Compile(e)) => {
+                    assert!(matches!(e, super::CompileError::NegativeRegister(_, 1)));
+                    assert_eq!(e.to_string(), "line 1: Negative register number -1");
+                }
+                _ => panic!("expected a negative register error for {:?}", src),
+            }
+        }
+        assert!("        save 100000, 1\n        decr -1, 0\n"
+            .parse::<super::Program>()
+            .is_ok());
+        for src in &[
+            "        save 99999999999999999999, 1\n        halt\n",
+            "        incr 100001\n        halt\n",
+            "        decrb 0, 100001, 1\n        halt\n",
+        ] {
+            let program: super::Program = src.parse().unwrap();
+            let mut machine = vm::MachineState::new_null();
+            let error = machine.run(&program).err().unwrap();
+            assert!(matches!(error.cause(), vm::RunError::RegisterLimit(_)));
+            let mut machine = vm::MachineState::new_null();
+            machine.set_memory_mode(super::MemoryMode::Wrapping(16));
+            assert!(machine.run(&program).is_ok(), "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn unbuffered_test() -> Result<(), Box<dyn std::error::Error>> {
+        use vm::Output;
+
+        struct Recorder(Vec<String>);
+
+        impl Output for Recorder {
+            fn write_char(&mut self, ch: char) -> std::io::Result<()> {
+                self.0.push(ch.to_string());
+                Ok(())
+            }
+
+            fn write_number(&mut self, n: &BigInt) -> std::io::Result<()> {
+                self.0.push(n.to_string());
+                Ok(())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.0.push(String::from("flush"));
+                Ok(())
+            }
+        }
+
+        let program = "        putc 65
+        putn 42
+        halt
+"
+        .parse()?;
+        let mut recorder = Recorder(Vec::new());
+        let mut machine = vm::MachineState::new(&mut recorder);
+        machine.set_unbuffered(true);
+        machine.run(&program)?;
+        drop(machine);
+        assert_eq!(recorder.0, vec!["A", "flush", "42", "flush", "flush"]);
+
+        let mut recorder = Recorder(Vec::new());
+        vm::MachineState::new(&mut recorder).run(&program)?;
+        assert_eq!(recorder.0, vec!["A", "42", "flush"]);
+        Ok(())
+    }
+
+    #[test]
+    fn character_literal_test() -> Result<(), Box<dyn std::error::Error>> {
+        for (literal, code) in &[
+            ("'A'", 65),
+            ("'\\n'", 10),
+            ("'\\''", 39),
+            ("'\\x41'", 65),
+            ("'\\u{1F600}'", 0x1f600),
+        ] {
+            let src = format!("        save 0, {}\n        halt\n", literal);
+            assert_eq!(run_program(&src)?, BigInt::from(*code));
+        }
+        for literal in &[
+            "'\\x80'",
+            "'\\xg1'",
+            "'\\u{110000}'",
+            "'\\u{D800}'",
+            "'\\q'",
+        ] {
+            match super::statements(&format!("        putc {}\n", literal)).next() {
+                Some(Err(super::SyntaxError {
+                    error: super::ParseError::InvalidEscape,
+                    ..
+                })) => {}
+                _ => panic!("expected an escape error for {}", literal),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn stray_bracket_test() {
+        for (src, column) in &[
+            ("        incr 5], 1\n", 15),
+            ("        save 0, [1]]\n", 20),
+            ("        decr 0, done ]\n", 22),
+        ] {
+            match super::statements(src).next() {
+                Some(Err(super::SyntaxError {
+                    error: super::ParseError::UnexpectedCloseBracket(']'),
+                    column: c,
+                    ..
+                })) => assert_eq!(c, *column, "{:?}", src),
+                _ => panic!("expected a stray bracket error for {:?}", src),
+            }
+        }
+    }
+
+    #[test]
+    fn instructions_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = include_str!("../testcase/square.asm").parse()?;
+        assert_eq!(program.instructions().count(), 29);
+        let (address, statement) = program
+            .instructions()
+            .find(|(_, statement)| matches!(statement, super::Statement::Halt))
+            .unwrap();
+        assert_eq!(address, 11);
+        assert_eq!(statement.to_string(), "halt");
+        Ok(())
+    }
+
+    #[test]
+    fn align_test() -> Result<(), Box<dyn std::error::Error>> {
+        let src = "        decr -1, target
+        align 4
+target  save 0, pc
+        align 1 : align 3
+        halt
+";
+        let program: super::Program = src.parse()?;
+        assert_eq!(program.len(), 7);
+        assert!(matches!(program[5], super::Statement::Nop));
+        assert_eq!(program[0].to_string(), "decr -1, 4, 1");
+        assert_eq!(run_program(src)?, BigInt::from(5));
+        assert!(matches!(
+            super::statements("        align 0\n").next(),
+            Some(Err(super::SyntaxError {
+                error: super::ParseError::InvalidAlignment,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            super::statements("        align 100000000000\n").next(),
+            Some(Err(super::SyntaxError {
+                error: super::ParseError::InvalidAlignment,
+                ..
+            }))
+        ));
+        // A label on an `align` line names the aligned address, whether or
+        // not padding was needed to reach it.
+        for (nops, address) in &[(4, 4), (5, 8)] {
+            let src = format!(
+                "{}lbl     align 4\n        save 0, lbl\n        halt\n",
+                "        nop\n".repeat(*nops)
+            );
+            let program: super::Program = src.parse()?;
+            assert_eq!(program.len(), address + 2);
+            assert_eq!(
+                program[*address].to_string(),
+                format!("save 0, {}", address)
+            );
+            assert_eq!(run_program(&src)?, BigInt::from(*address));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn program_counter_position_test() -> Result<(), Box<dyn std::error::Error>> {
+        for src in &[
+            "        incr pc, 1\n",
+            "        decr pc, 0\n",
+            "        puts pc\n",
+        ] {
+            assert!(
+                matches!(
+                    super::statements(src).next(),
+                    Some(Err(super::SyntaxError {
+                        error: super::ParseError::ProgramCounterIndex,
+                        column: 14,
+                        ..
+                    }))
+                ),
+                "{:?}",
+                src
+            );
+        }
+        // pc stands for the address of the following instruction.
+        let src = "        nop
+        nop
+        incr 0, pc
+        decr 0, pc, 100
+        halt
+";
+        assert_eq!(run_program(src)?, BigInt::from(3));
+        Ok(())
+    }
+
+    #[test]
+    fn compile_test() {
+        let (program, diagnostics) = super::compile(
+            "        save 0, 1
+        halt
+        incr 0
+",
+        )
+        .unwrap();
+        assert_eq!(program.len(), 3);
+        assert!(!diagnostics.is_empty());
+        assert!(diagnostics
+            .iter()
+            .all(|d| d.severity == super::Severity::Warning));
+
+        let errors = super::compile("        incr\n        halt 1\n")
+            .err()
+            .unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[1].kind, super::Lint::Syntax);
+        assert_eq!((errors[1].line, errors[1].column), (Some(2), Some(14)));
+        assert_eq!(errors[1].severity, super::Severity::Error);
+
+        let errors = super::compile("        decr 0, nowhere\n        halt\n")
+            .err()
+            .unwrap();
+        assert_eq!(errors[0].kind, super::Lint::Compile);
+        assert_eq!(errors[0].line, Some(1));
+    }
+
+    #[test]
+    fn with_capacity_test() -> Result<(), Box<dyn std::error::Error>> {
+        // Fills registers 1..=500 with their own index and sums them in
+        // register 1000.
+        let program = "        save 1, 500
+fill    save [1], [1]
+        decr 1, sum
+        decr -1, fill
+sum     save 1, 500
+loop    incr 1000, [[1]]
+        decr 1, done
+        decr -1, loop
+done    save 0, [1000]
+        halt
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        let expected = vm::MachineState::new(&mut buffer).run(&program)?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::with_capacity(&mut buffer, 1001);
+        assert_eq!(machine.run(&program)?, expected);
+        assert_eq!(expected, BigInt::from(125250));
+        assert_eq!(machine.allocated_registers(), 1001);
+        Ok(())
+    }
+
+    #[test]
+    fn data_directive_test() -> Result<(), Box<dyn std::error::Error>> {
+        let src = "        data 10, 1, 2, 3
+        data 20, 'a', end ; table
+        putn [11]
+end     halt
+";
+        let program: super::Program = src.parse()?;
+        assert_eq!(program.len(), 2);
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        assert!(matches!(
+            machine.run_until(&program, &|_| true)?,
+            vm::RunState::Stopped(0)
+        ));
+        let registers: Vec<BigInt> = (10..13).map(|i| machine.read_register(i)).collect();
+        assert_eq!(
+            registers,
+            vec![BigInt::from(1), BigInt::from(2), BigInt::from(3)]
+        );
+        assert_eq!(machine.read_register(20), BigInt::from(97));
+        assert_eq!(machine.read_register(21), BigInt::from(1));
+
+        let restored = super::Program::from_bytecode(&program.to_bytecode())?;
+        assert_eq!(restored.data(), program.data());
+        assert_eq!(
+            super::format_source(src)?,
+            "\tdata 10, 1, 2, 3\n\tdata 20, 'a', end\t; table\n\tputn [11]\nend\thalt\n"
+        );
+        assert!(matches!(
+            "        data 100000, 1, 2\n        halt\n".parse::<super::Program>(),
+            Err(super::AssembleError::Compile(
+                super::CompileError::RegisterLimit(_, 1)
+            ))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn constant_test() -> Result<(), Box<dyn std::error::Error>> {
+        let src = "limit   equ 10 ; loop count
+        save 1, limit
+        putn done
+done    halt
+";
+        assert_eq!(run_to_string(src)?, "2");
+        let mut buffer = Vec::new();
+        let program = src.parse()?;
+        let mut machine = vm::MachineState::new(&mut buffer);
+        machine.run(&program)?;
+        assert_eq!(machine.read_register(1), BigInt::from(10));
+        assert_eq!(
+            super::format_source(src)?,
+            "limit\tequ 10\t; loop count\n\tsave 1, limit\n\tputn done\ndone\thalt\n"
+        );
+        match "limit   equ 10\n        decr 0, limit\n        halt\n".parse::<super::Program>() {
+            Err(super::AssembleError::Compile(super::CompileError::ConstantAsAddress(name, 2))) => {
+                assert_eq!(name, "limit")
+            }
+            _ => panic!("expected a constant used as an address to fail"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn null_output_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = "        save 1, 3
+loop    putn [1]
+        incr 0, [1]
+        decr 1, done
+        decr -1, loop
+done    halt
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        let expected = vm::MachineState::new(&mut buffer).run(&program)?;
+        assert_eq!(buffer, b"3210");
+        let mut machine = vm::MachineState::new_null();
+        assert_eq!(machine.run(&program)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_error_kind_test() {
+        match "        decr 0, nowhere\n        halt\n".parse::<super::Program>() {
+            Err(super::AssembleError::Compile(super::CompileError::UnknownLabel(label, 1))) => {
+                assert_eq!(label, "nowhere")
+            }
+            _ => panic!("expected a compile error"),
+        }
+        match "        jump 0\n".parse::<super::Program>() {
+            Err(super::AssembleError::Parse(super::SyntaxError {
+                error: super::ParseError::UnknownMnemonic,
+                ..
+            })) => {}
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn pointer_index_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        save 2, 5
+        save 5, 7
+        save [[2]], 9
+        incr [[2]], 1
+        halt
+"
+        .parse()?;
+        assert_eq!(program[2].to_string(), "save [[2]], 9");
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        machine.run(&program)?;
+        assert_eq!(machine.read_register(7), BigInt::from(10));
+        assert_eq!(machine.read_register(5), BigInt::from(7));
+        let restored = super::Program::from_bytecode(&program.to_bytecode())?;
+        assert_eq!(restored[2].to_string(), "save [[2]], 9");
+        Ok(())
+    }
+
+    #[test]
+    fn call_test() -> Result<(), Box<dyn std::error::Error>> {
+        let src = "        save 1, 3
+        call twice
+        putn [0]
+        halt
+twice   call add
+add     incr 0, [1]
+        ret
+";
+        assert_eq!(run_to_string(src)?, "6");
+
+        let program = "        save 1, 100
+        call down
+        halt
+down    decr 1, done
+        call down
+done    ret
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        machine.run(&program)?;
+        machine.reset();
+        machine.set_max_call_depth(10);
+        match machine.run(&program) {
+            Err(e) => assert!(matches!(e.cause(), vm::RunError::StackOverflow(10))),
+            Ok(_) => panic!("expected a stack overflow"),
+        }
+        assert!(matches!(
+            run_program("        ret\n"),
+            Err(e) if e.to_string().contains("ret without a matching call")
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn no_reachable_halt_lint_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        save 0, 1
+loop    decr -1, loop
+        halt
+"
+        .parse()?;
+        let diagnostics = program.lint();
+        assert!(diagnostics
+            .iter()
+            .any(|d| d.kind == super::Lint::NoReachableHalt && d.line.is_none()));
+        let program: super::Program = include_str!("../testcase/square.asm").parse()?;
+        assert!(program
+            .lint()
+            .iter()
+            .all(|d| d.kind != super::Lint::NoReachableHalt));
+        Ok(())
+    }
+
+    #[test]
+    fn register_if_set_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = "        save 3, 7
+        save 5000, 0
+        halt
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        machine.run(&program)?;
+        assert_eq!(machine.register_if_set(3), Some(&BigInt::from(7)));
+        assert_eq!(machine.register_if_set(5000), Some(&BigInt::from(0)));
+        assert_eq!(machine.register_if_set(4999), None);
+        assert_eq!(machine.register_if_set(50), None);
+        assert_eq!(machine.read_register(50), BigInt::from(0));
+        Ok(())
+    }
+
+    #[test]
+    fn output_pointer_value_test() -> Result<(), Box<dyn std::error::Error>> {
+        let src = "        save 2, 7
+        save 7, 65
+        putn [[2]]
+        putc [[2]]
+        save 2, 200000
+        putn [[2]]
+        save 2, -1
+        putn [[2]]
+        halt
+";
+        assert_eq!(run_to_string(src)?, "65A00");
+        Ok(())
+    }
+
+    #[test]
+    fn parser_fuzz_test() {
+        const PIECES: &[&str] = &[
+            "        ",
+            "incr",
+            "decr",
+            "save",
+            "putc",
+            "call",
+            "ret",
+            "halt",
+            "times",
+            "align",
+            "data",
+            "equ",
+            " ",
+            "\t",
+            "\n",
+            ",",
+            ":",
+            "[",
+            "]",
+            "[[",
+            "]]",
+            "(",
+            ")",
+            "-",
+            "0",
+            "7",
+            "99999999999999999999999999",
+            "pc",
+            "loop",
+            "'",
+            "\\",
+            "\\x",
+            "\\u{",
+            "}",
+            ";",
+            "//",
+            "é",
+            "漢",
+            "\r",
+            "\u{0}",
+        ];
+        // A fixed linear congruential generator keeps the inputs reproducible.
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = |bound: usize| {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (seed >> 33) as usize % bound
+        };
+        for _ in 0..500 {
+            let mut src = String::new();
+            for _ in 0..next(24) {
+                if next(8) == 0 {
+                    let bytes: Vec<u8> = (0..next(4) + 1).map(|_| next(256) as u8).collect();
+                    src.push_str(&String::from_utf8_lossy(&bytes));
+                } else {
+                    src.push_str(PIECES[next(PIECES.len())]);
+                }
+            }
+            let _ = src.parse::<super::Program>();
+            let _ = super::parse_ast(&src);
+            let _ = super::format_source(&src);
+            let _ = super::statements(&src).count();
+        }
+    }
+
+    #[test]
+    fn output_width_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = "        putn -1
+        putc 32
+        putn 256
+        putc 32
+        putn 127
+        putc 32
+        puth -2
+        halt
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        machine.set_output_width(Some(8));
+        machine.run(&program)?;
+        drop(machine);
+        assert_eq!(String::from_utf8(buffer)?, "255 0 127 fe");
+        Ok(())
+    }
+
+    #[test]
+    fn compile_file_test() -> Result<(), Box<dyn std::error::Error>> {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testcase/factorial.asm");
+        let program = super::compile_file(path)?;
+        let mut machine = vm::MachineState::new_null();
+        assert_eq!(machine.run(&program)?, BigInt::from(120));
+        match super::compile_file("testcase/missing.asm") {
+            Err(super::FileError::Io(e)) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::NotFound)
+            }
+            _ => panic!("expected an IO error"),
+        }
+        let path =
+            std::env::temp_dir().join(format!("aaron-asm-{}-compile-file.asm", std::process::id()));
+        std::fs::write(&path, b"        putc '\xff'\n")?;
+        let encoding = super::compile_file(&path);
+        std::fs::write(&path, "        jump 0\n")?;
+        let parse = super::compile_file(&path);
+        std::fs::remove_file(&path)?;
+        match encoding {
+            Err(super::FileError::Encoding(e)) => assert_eq!(e.utf8_error().valid_up_to(), 14),
+            _ => panic!("expected an encoding error"),
+        }
+        match parse {
+            Err(super::FileError::Assemble {
+                source,
+                error: super::AssembleError::Parse(e),
+            }) => {
+                assert_eq!(source, "        jump 0\n");
+                assert_eq!(e.line, 1);
+            }
+            _ => panic!("expected a parse error"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn literal_error_test() -> Result<(), Box<dyn std::error::Error>> {
+        for (src, expected) in &[
+            (
+                "        putc '\n",
+                super::ParseError::UnterminatedCharLiteral,
+            ),
+            (
+                "        putc 'A\n",
+                super::ParseError::UnterminatedCharLiteral,
+            ),
+            ("        putc ''\n", super::ParseError::EmptyCharLiteral),
+            ("        putc '\\q'\n", super::ParseError::InvalidEscape),
+            (
+                "        data 0, \"abc\n",
+                super::ParseError::UnterminatedString,
+            ),
+            (
+                "        data 0, \"a\\qc\"\n",
+                super::ParseError::InvalidEscape,
+            ),
+        ] {
+            match super::statements(src).next() {
+                Some(Err(e)) => assert_eq!(e.error.to_string(), expected.to_string()),
+                _ => panic!("expected a syntax error for {}", src),
+            }
+        }
+        let program: super::Program = "        data 0, \"ab\\n\", 0\n        halt\n".parse()?;
+        assert_eq!(
+            program.data()[0].1,
+            vec![
+                BigInt::from(97),
+                BigInt::from(98),
+                BigInt::from(10),
+                BigInt::from(0)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn putn_base_test() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            run_to_string(
+                "        putn 255, 16\n        putc ' '\n        putn 255\n        halt\n"
+            )?,
+            "ff 255"
+        );
+        assert_eq!(
+            run_to_string("        save 1, 2\n        putn -5, [1]\n        halt\n")?,
+            "-101"
+        );
+        let program: super::Program = "        putn 5, 37\n        halt\n".parse()?;
+        assert_eq!(program.to_string(), "putn 5, 37\nhalt\n");
+        let restored = super::Program::from_bytecode(&program.to_bytecode())?;
+        assert_eq!(restored.to_string(), program.to_string());
+        let mut machine = vm::MachineState::new_null();
+        match machine.run(&program) {
+            Err(e) => assert!(matches!(e.cause(), vm::RunError::InvalidRadix(_))),
+            Ok(_) => panic!("expected an invalid radix"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn driver_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = include_str!("../testcase/fibonacci.asm").parse()?;
+        let mut buffer = Vec::new();
+        let mut driver = super::Driver::new(vm::MachineState::new(&mut buffer), &program);
+        let mut bursts = 0;
+        let result = loop {
+            bursts += 1;
+            match driver.run_for(5)? {
+                super::DriveOutcome::Halted(result) => break result,
+                super::DriveOutcome::Yielded => {}
+            }
+        };
+        assert!(bursts > 1);
+        assert_eq!(result, BigInt::from_str("354224848179261915075")?);
+        assert_eq!(
+            driver.run_for(5)?,
+            super::DriveOutcome::Halted(BigInt::from_str("354224848179261915075")?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn missing_space_test() {
+        match super::statements("        incr0, 1\n").next() {
+            Some(Err(e)) => {
+                assert_eq!(e.column, 13);
+                assert_eq!(
+                    e.error.to_string(),
+                    "Missing space between incr and its operand"
+                );
+            }
+            _ => panic!("expected a missing space error"),
+        }
+        match super::statements("        jump0, 1\n").next() {
+            Some(Err(e)) => assert_eq!(e.error.to_string(), "Unknown mnemonic"),
+            _ => panic!("expected an unknown mnemonic"),
+        }
+    }
+
+    #[test]
+    fn run_batch_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program =
+            "        gets 10\n        puts 10\n        putc 10\n        halt\n".parse()?;
+        let inputs = vec![b"one\n".to_vec(), b"two".to_vec(), Vec::new()];
+        let outputs: Vec<String> = super::run_batch(&program, &inputs)
+            .into_iter()
+            .collect::<Result<_, _>>()?;
+        assert_eq!(outputs, vec!["one\n", "two\n", "\n"]);
+        let failing: super::Program = "        decr -1, 5\n".parse()?;
+        assert!(super::run_batch(&failing, &inputs)
+            .iter()
+            .all(Result::is_err));
+        Ok(())
+    }
+
+    #[test]
+    fn dead_store_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        save 0, 1
+        save 1, 5
+        save 0, 2
+        halt
+"
+        .parse()?;
+        let dead: Vec<_> = program
+            .lint()
+            .into_iter()
+            .filter(|d| d.kind == super::Lint::DeadStore)
+            .map(|d| (d.address, d.line))
+            .collect();
+        assert_eq!(dead, vec![(Some(0), Some(1))]);
+        for src in &[
+            "        save 0, 1\n        putn [0]\n        save 0, 2\n        halt\n",
+            "        save 0, 1\n        incr 0\n        save 0, 2\n        halt\n",
+            "        save 0, 1\n        decr 1, end\n        save 0, 2\nend     halt\n",
+            "        save 0, 1\n        save 0, [0]\n        halt\n",
+        ] {
+            let program: super::Program = src.parse()?;
+            assert_eq!(program.dead_stores(), vec![], "{}", src);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_text_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = include_str!("../testcase/factorial.asm").parse()?;
+        assert_eq!(
+            program.to_canonical_text(),
+            "; aaron-asm canonical 1
+0000  save    1, 5
+0001  save    2, 6
+0002  save    3, 7
+0003  save    0, 1
+0004  decr    1, 19, 1
+0005  save    7, [1]
+0006  incr    7, 1
+0007  save    5, 8
+0008  incr    5, 2
+0009  decr    -1, 12, 1
+0010  save    0, [6]
+0011  decr    -1, 4, 1
+0012  save    [2], 0
+0013  save    8, [[3]]
+0014  decr    [3], 17, 1
+0015  incr    [2], [[4]]
+0016  decr    -1, 14, 1
+0017  save    [3], [8]
+0018  decr    -1, [5], 1
+0019  halt
+"
+        );
+        let program: super::Program =
+            "        data 3, 'a'\n        putn 7\n        halt\n".parse()?;
+        assert_eq!(
+            program.to_canonical_text(),
+            "; aaron-asm canonical 1\ndata 3, 97\n0000  putn    7, 10\n0001  halt\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn run_cancellable_test() -> Result<(), Box<dyn std::error::Error>> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let program: super::Program = "loop    decr -1, loop\n".parse()?;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&cancelled);
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            flag.store(true, Ordering::Relaxed);
+        });
+        let mut machine = vm::MachineState::new_null();
+        let result = machine.run_cancellable(&program, &cancelled);
+        canceller.join().unwrap();
+        assert!(matches!(result, Err(vm::RunError::Cancelled)));
+
+        let program: super::Program = "        save 0, 3\n        halt\n".parse()?;
+        let mut machine = vm::MachineState::new_null();
+        let result = machine.run_cancellable(&program, &AtomicBool::new(false))?;
+        assert_eq!(result, BigInt::from(3));
+        Ok(())
+    }
+
+    #[test]
+    fn trailing_separator_test() {
+        for (src, column) in &[
+            ("        save 0,\n", 15),
+            ("        incr 0, \n", 15),
+            ("        decr 0, 3, ; count\n", 18),
+            ("        putn 1, : halt\n", 15),
+        ] {
+            match super::statements(src).next() {
+                Some(Err(e)) => {
+                    assert_eq!(e.error.to_string(), "Expected an operand after ','");
+                    assert_eq!(e.column, *column, "{}", src);
+                }
+                _ => panic!("expected a missing operand error for {}", src),
+            }
+        }
+    }
+
+    #[test]
+    fn wrapping_memory_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        save 6, 5
+        incr 2, 3
+        save 0, [10]
+        decr -1, end
+end     halt
+"
+        .parse()?;
+        let mut machine = vm::MachineState::new_null();
+        machine.set_memory_mode(super::MemoryMode::Wrapping(4));
+        assert_eq!(machine.run(&program)?, BigInt::from(8));
+        assert_eq!(machine.read_register(6), BigInt::from(8));
+        assert_eq!(machine.register_if_set(2), Some(&BigInt::from(8)));
+        assert!(machine.allocated_registers() <= 4);
+        Ok(())
+    }
+
+    #[test]
+    fn run_counting_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program = include_str!("../testcase/square.asm").parse()?;
+        let mut machine = vm::MachineState::new_null();
+        let (result, count) = machine.run_counting(&program)?;
+        let mut machine = vm::MachineState::new_null();
+        let (expected, stats) = machine.run_with_stats(&program)?;
+        assert_eq!(result, expected);
+        assert_eq!(count, stats.total());
+        assert_eq!(count, 150);
+        Ok(())
+    }
+
+    #[test]
+    fn comment_at_end_of_file_test() -> Result<(), Box<dyn std::error::Error>> {
+        for src in &[
+            "        halt ; final comment",
+            "        halt // final comment",
+            "        halt ;",
+            "        halt\n; final comment",
+        ] {
+            let program: super::Program = src.parse()?;
+            assert_eq!(program.len(), 1, "{}", src);
+            assert!(matches!(program[0], super::Statement::Halt));
+        }
+        assert_eq!(
+            super::format_source("        halt ; final comment")?,
+            "\thalt\t; final comment\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn machine_builder_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "loop    putc 'a' : decr -1, loop\n".parse()?;
+        let builder = super::MachineBuilder::new().max_steps(100).max_output(10);
+
+        let mut buffer = Vec::new();
+        let mut machine = builder.clone().build(&mut buffer);
+        let error = machine.run(&program).err().unwrap();
+        assert!(matches!(error.cause(), vm::RunError::OutputLimit(10)));
+        drop(machine);
+        assert_eq!(buffer, b"aaaaaaaaaa");
+
+        let program: super::Program = "loop    decr -1, loop\n".parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = builder.build(&mut buffer);
+        let error = machine.run(&program).err().unwrap();
+        assert!(matches!(error, vm::RunError::StepLimit(100)));
+
+        let program: super::Program = "        putn 123456, 10\n        halt\n".parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = super::MachineBuilder::new()
+            .max_output(6)
+            .digit_grouping(',')
+            .build(&mut buffer);
+        let error = machine.run(&program).err().unwrap();
+        assert!(matches!(error.cause(), vm::RunError::OutputLimit(6)));
+
+        let program: super::Program = "        save 8, 1\n        halt\n".parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = super::MachineBuilder::new()
+            .max_registers(8)
+            .build(&mut buffer);
+        let error = machine.run(&program).err().unwrap();
+        assert!(matches!(error.cause(), vm::RunError::MemoryLimit(8)));
+        Ok(())
+    }
+
+    #[test]
+    fn decrb_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        save 0, 3
+        decrb 0, 1, 5
+        save 2, 7
+        decrb 2, 3, [0]
+        halt
+"
+        .parse()?;
+        let mut machine = vm::MachineState::new_null();
+        machine.write_register(3, BigInt::from(9))?;
+        assert_eq!(machine.run(&program)?, BigInt::from(0));
+        assert_eq!(machine.read_register(1), BigInt::from(1));
+        assert_eq!(machine.read_register(2), BigInt::from(7));
+        assert_eq!(machine.read_register(3), BigInt::from(0));
+        assert_eq!(program[1].to_string(), "decrb 0, 1, 5");
+        let restored = super::Program::from_bytecode(&program.to_bytecode())?;
+        assert_eq!(restored.to_string(), program.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn savepc_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        nop
+        nop
+        save 1, pc
+        savepc 0
+        halt
+"
+        .parse()?;
+        let mut machine = vm::MachineState::new_null();
+        assert_eq!(machine.run(&program)?, BigInt::from(3));
+        assert_eq!(machine.read_register(1), BigInt::from(3));
+        let restored = super::Program::from_bytecode(&program.to_bytecode())?;
+        assert_eq!(restored.to_string(), program.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn touched_registers_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        save 9, 0
+        incr 5, [7]
+        save 0, [2000]
+        save 3000, 1
+        halt
+"
+        .parse()?;
+        let mut machine = vm::MachineState::new_null();
+        machine.run(&program)?;
+        assert_eq!(machine.touched_registers(), vec![0, 5, 9, 3000]);
+        machine.reset();
+        assert_eq!(machine.touched_registers(), Vec::<usize>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn loadcode_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        loadcode 1, end
+        loadcode 2, pc
+        loadcode 0, 100
+end     halt
+"
+        .parse()?;
+        let mut machine = vm::MachineState::new_null();
+        let error = machine.run(&program).err().unwrap();
+        assert!(matches!(error.cause(), vm::RunError::CodeNotReadable));
+
+        let mut machine = vm::MachineState::new_null();
+        machine.set_code_readable(true);
+        assert_eq!(machine.run(&program)?, BigInt::from(0));
+        assert_eq!(machine.read_register(1), BigInt::from(8));
+        // `loadcode 0, 100`: opcode 17, an immediate index 0 and an
+        // immediate address 100, each a tag, a u32 length and the bytes.
+        assert_eq!(
+            machine.read_register(2),
+            BigInt::from_bytes_le(
+                num_bigint::Sign::Plus,
+                &[17, 0, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 100]
+            )
+        );
+        let restored = super::Program::from_bytecode(&program.to_bytecode())?;
+        assert_eq!(restored.to_string(), program.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn prepared_dispatch_test() -> Result<(), Box<dyn std::error::Error>> {
+        let loop_source = "        save 5, 3
+        save 1, [5]
+loop    decr 1, end
+        incr 6, [1]
+        decr -1, loop
+end     incr 0, [6]
+        halt
+";
+        // The loop also runs with its registers folded into four slots.
+        let cases = [
+            (include_str!("../testcase/fibonacci.asm"), None),
+            (include_str!("../testcase/factorial.asm"), None),
+            (include_str!("../testcase/square.asm"), None),
+            (loop_source, None),
+            (loop_source, Some(4)),
+        ];
+        for &(source, mode) in cases.iter() {
+            let program: super::Program = source.parse()?;
+            let mut compiled = vm::MachineState::new_null();
+            let mut interpreted = vm::MachineState::new_null();
+            if let Some(size) = mode {
+                compiled.set_memory_mode(super::MemoryMode::Wrapping(size));
+                interpreted.set_memory_mode(super::MemoryMode::Wrapping(size));
+            }
+            let result = compiled.run(&program).map_err(|e| e.to_string());
+            let expected = loop {
+                match interpreted.step(&program) {
+                    Ok(true) => break Ok(interpreted.read_register(0)),
+                    Ok(false) => {}
+                    Err(e) => break Err(e.to_string()),
+                }
+            };
+            assert_eq!(result, expected);
+            assert_eq!(compiled.branch_histogram(), interpreted.branch_histogram());
+            assert_eq!(
+                compiled.touched_registers(),
+                interpreted.touched_registers()
+            );
+        }
+
+        // Editing the statements drops the prepared form.
+        let mut program: super::Program = "        save 0, 1\n        halt\n".parse()?;
+        assert_eq!(vm::MachineState::new_null().run(&program)?, BigInt::from(1));
+        program[0] = "        save 0, 2\n".parse::<super::Program>()?[0].clone();
+        assert_eq!(vm::MachineState::new_null().run(&program)?, BigInt::from(2));
+        Ok(())
+    }
+
+    #[test]
+    fn format_label_gap_test() -> Result<(), Box<dyn std::error::Error>> {
+        let source = "; header
+n equ 3
+        save 1, n
+loop    decr 1, finish ; count down
+    ; indented
+        incr 0
+        decr -1, loop
+finish  halt
+        data 9, 7
+";
+        let options = super::FormatOptions { label_gap: Some(2) };
+        let formatted = super::format_source_with(source, &options)?;
+        assert_eq!(
+            formatted,
+            "; header
+n       equ 3
+        save 1, n
+loop    decr 1, finish\t; count down
+        ; indented
+        incr 0
+        decr -1, loop
+finish  halt
+        data 9, 7
+"
+        );
+        assert_eq!(super::format_source_with(&formatted, &options)?, formatted);
+        // Without a gap the layout stays tab separated.
+        assert!(super::format_source(source)?.contains("\nfinish\thalt\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn echo_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "loop    echo
+        incr 0
+        decr -1, loop
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        let mut input = &b"Hi"[..];
+        machine.set_input(&mut input);
+        // The end of input halts the machine after two characters.
+        assert_eq!(machine.run(&program)?, BigInt::from(2));
+        drop(machine);
+        assert_eq!(String::from_utf8(buffer)?, "Hi");
+
+        let mut buffer = Vec::new();
+        let mut machine = vm::MachineState::new(&mut buffer);
+        let mut input = "あ\n".as_bytes();
+        machine.set_input(&mut input);
+        assert_eq!(machine.run(&program)?, BigInt::from(2));
+        drop(machine);
+        assert_eq!(String::from_utf8(buffer)?, "あ\n");
+
+        // Without any input the first `echo` halts.
+        assert_eq!(vm::MachineState::new_null().run(&program)?, BigInt::from(0));
+        let restored = super::Program::from_bytecode(&program.to_bytecode())?;
+        assert_eq!(restored.to_string(), program.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn run_status_test() -> Result<(), Box<dyn std::error::Error>> {
+        use super::HaltReason;
+        use std::sync::atomic::AtomicBool;
+
+        let halting: super::Program = "        incr 0, 3\n        halt\n".parse()?;
+        let mut machine = vm::MachineState::new_null();
+        assert_eq!(
+            machine.run_status(&halting, None)?,
+            (BigInt::from(3), HaltReason::ExplicitHalt)
+        );
+
+        let falling: super::Program = "        incr 0, 3\n        incr 0\n".parse()?;
+        assert!(vm::MachineState::new_null().run(&falling).is_err());
+        let mut machine = vm::MachineState::new_null();
+        assert_eq!(
+            machine.run_status(&falling, None)?,
+            (BigInt::from(4), HaltReason::FellOffEnd)
+        );
+
+        // Jumping further than one past the end is still an error.
+        let jumping: super::Program = "        decr -1, 5\n        halt\n".parse()?;
+        let error = vm::MachineState::new_null()
+            .run_status(&jumping, None)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.cause(),
+            vm::RunError::InvalidProgramCounter(_)
+        ));
+
+        let looping: super::Program = "loop    incr 0\n        decr -1, loop\n".parse()?;
+        let mut machine = vm::MachineState::new_null();
+        machine.set_max_steps(Some(10));
+        assert_eq!(
+            machine.run_status(&looping, None)?,
+            (BigInt::from(5), HaltReason::StepLimit)
+        );
+        let cancelled = AtomicBool::new(true);
+        let mut machine = vm::MachineState::new_null();
+        assert_eq!(
+            machine.run_status(&looping, Some(&cancelled))?,
+            (BigInt::from(0), HaltReason::Cancelled)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn radix_suffix_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        save 1, 1010b
+        save 2, 0FFh
+        save 3, 17o
+        save 4, -11B
+        save 00101b, 7
+        halt
+"
+        .parse()?;
+        let mut machine = vm::MachineState::new_null();
+        machine.run(&program)?;
+        assert_eq!(machine.read_register(1), BigInt::from(10));
+        assert_eq!(machine.read_register(2), BigInt::from(255));
+        assert_eq!(machine.read_register(3), BigInt::from(15));
+        assert_eq!(machine.read_register(4), BigInt::from(-3));
+        assert_eq!(machine.read_register(5), BigInt::from(7));
+        // Without a leading digit it is a label, even if it spells a number.
+        let program: super::Program = "FFh     save 0, FFh\n        halt\n".parse()?;
+        let mut machine = vm::MachineState::new_null();
+        assert_eq!(machine.run(&program)?, BigInt::from(0));
+        // Digits that don't fit the radix are not a number.
+        assert!("        save 1, 12b\n        halt\n"
+            .parse::<super::Program>()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_label_test() {
+        let src = "; loop twice
+loop    decr 1, end
+        incr 0
+loop    decr -1, loop
+end     halt
+";
+        match src.parse::<super::Program>() {
+            Err(super::AssembleError::Compile(super::CompileError::DuplicateLabel(
+                label,
+                first,
+                second,
+            ))) => {
+                assert_eq!(label, "loop");
+                assert_eq!((first, second), (2, 4));
+            }
+            _ => panic!("expected a duplicate label error"),
+        }
+        let diagnostics = super::compile(src).err().unwrap();
+        assert_eq!(diagnostics[0].line, Some(4));
+        assert_eq!(
+            diagnostics[0].message,
+            "Label loop is already defined at line 2"
+        );
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "error: line 4: Label loop is already defined at line 2"
+        );
+
+        // An unused label is reported where it is defined.
+        let (_, warnings) = super::compile("        save 1, 2\n\nspare   halt\n").unwrap();
+        assert_eq!(warnings[0].kind, super::Lint::UnusedLabel);
+        assert_eq!(warnings[0].line, Some(3));
+    }
+
+    #[test]
+    fn empty_data_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        data 0, \"\"\n        halt\n".parse()?;
+        assert!(program.data().is_empty());
+        assert_eq!(vm::MachineState::new_null().run(&program)?, BigInt::from(0));
+        let program: super::Program = "        data 5, \"\"\n        halt\n".parse()?;
+        assert_eq!(program.max_static_register(), Some(0));
+
+        // A statement after `data` would otherwise be dropped.
+        match super::statements("        data 10, 1 : incr 0\n").next() {
+            Some(Err(e)) => {
+                assert_eq!(
+                    e.error.to_string(),
+                    super::ParseError::NotAlone("data").to_string()
+                );
+                assert_eq!(e.column, 20);
+            }
+            _ => panic!("expected a syntax error"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn constant_not_alone_test() {
+        match super::statements("x equ 5 : incr 0\n        halt\n").next() {
+            Some(Err(e)) => {
+                assert_eq!(
+                    e.error.to_string(),
+                    super::ParseError::NotAlone("equ").to_string()
+                );
+                assert_eq!((e.line, e.column), (1, 9));
+            }
+            _ => panic!("expected a syntax error"),
+        }
+    }
+
+    #[test]
+    fn corrupted_bytecode_test() -> Result<(), Box<dyn std::error::Error>> {
+        use super::BytecodeError;
+
+        // The magic, the line number and the opcode come before the tag of
+        // `putn`'s operand.
+        let program: super::Program = "        putn 5\n        halt\n".parse()?;
+        let mut bytes = program.to_bytecode();
+        assert_eq!(bytes[10], 0);
+        bytes[10] = 4;
+        assert_eq!(
+            super::Program::from_bytecode(&bytes).err(),
+            Some(BytecodeError::InvalidLabel)
+        );
+
+        let program: super::Program = "        decr -1, 9\n        halt\n".parse()?;
+        assert_eq!(
+            super::Program::from_bytecode(&program.to_bytecode()).err(),
+            Some(BytecodeError::InvalidJumpTarget(BigInt::from(9)))
+        );
+
+        let mut bytes = b"AARN\x01".to_vec();
+        bytes.extend_from_slice(&[0, 0, 0, 0, 11, 3, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            super::Program::from_bytecode(&bytes).err(),
+            Some(BytecodeError::EmptyData)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn wrapped_puts_test() -> Result<(), Box<dyn std::error::Error>> {
+        let program: super::Program = "        save 0, 'a'
+        save 1, 'b'
+        save 2, 'c'
+        puts 1
+        halt
+"
+        .parse()?;
+        let mut buffer = Vec::new();
+        {
+            let mut machine = vm::MachineState::new(&mut buffer);
+            machine.set_memory_mode(super::MemoryMode::Wrapping(3));
+            machine.run(&program)?;
+        }
+        assert_eq!(buffer, b"bca");
+        Ok(())
+    }
+
+    #[test]
+    fn format_source_forms_test() -> Result<(), Box<dyn std::error::Error>> {
+        let source = "start   times 3  nop ; pad
+        align 4
+mask  equ   0FFh
+        save 1 ,mask:putc ','   ; comma
+        data 9,\"a, b\" , ' '
+        incr 2
+        decr -1,start
+";
+        let formatted = super::format_source(source)?;
+        assert_eq!(
+            formatted,
+            "start\ttimes 3 nop\t; pad
+\talign 4
+mask\tequ 0FFh
+\tsave 1, mask : putc ','\t; comma
+\tdata 9, \"a, b\", ' '
+\tincr 2
+\tdecr -1, start
+"
+        );
+        assert_eq!(super::format_source(&formatted)?, formatted);
+        let program: super::Program = source.parse()?;
+        let reformatted: super::Program = formatted.parse()?;
+        assert_eq!(reformatted.to_string(), program.to_string());
         Ok(())
     }
 }
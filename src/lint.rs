@@ -0,0 +1,311 @@
+use crate::compiler::{parse_ast, SyntaxError};
+use crate::syntax_tree::{Address, CompileError, Index, Number, Program, Statement, Value};
+use num_traits::{Signed, ToPrimitive, Zero};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lint {
+    SelfLoop,
+    FallOffEnd,
+    Unreachable,
+    UnusedLabel,
+    NoReachableHalt,
+    /// Register 0 is written and written again before anything reads it.
+    DeadStore,
+    /// The source does not parse.
+    Syntax,
+    /// The source parses but can not be assembled.
+    Compile,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: Lint,
+    pub message: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub address: Option<usize>,
+}
+
+impl Diagnostic {
+    fn warning(program: &Program, kind: Lint, address: usize, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            kind,
+            message,
+            line: program.line(address),
+            column: None,
+            address: Some(address),
+        }
+    }
+
+    fn syntax(error: &SyntaxError) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            kind: Lint::Syntax,
+            message: error.error.to_string(),
+            line: Some(error.line),
+            column: Some(error.column),
+            address: None,
+        }
+    }
+
+    fn compile(error: &CompileError) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            kind: Lint::Compile,
+            message: error.message(),
+            line: Some(error.line()),
+            column: None,
+            address: None,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.severity)?;
+        if let Some(line) = self.line {
+            write!(f, ": line {}", line)?;
+        }
+        if let Some(column) = self.column {
+            write!(f, ", column {}", column)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+enum Flow {
+    Next,
+    Jump(usize),
+    Branch(usize),
+    Dynamic,
+    Stop,
+}
+
+fn flow(statement: &Statement) -> Flow {
+    match statement {
+        Statement::Halt => Flow::Stop,
+        Statement::Decr(index, address, value) => {
+            let target = match address {
+                Address::Immediate(n) => match n.to_usize() {
+                    Some(target) => target,
+                    None => return Flow::Dynamic,
+                },
+                _ => return Flow::Dynamic,
+            };
+            // A negative register always reads as zero, so `decr -1, target`
+            // is an unconditional jump.
+            match (index, value) {
+                (Index::Direct(i), Value::Immediate(v)) if i.is_negative() && v.is_positive() => {
+                    Flow::Jump(target)
+                }
+                _ => Flow::Branch(target),
+            }
+        }
+        // The callee returns to the next instruction.
+        Statement::Call(Address::Immediate(n)) => match n.to_usize() {
+            Some(target) => Flow::Branch(target),
+            None => Flow::Dynamic,
+        },
+        Statement::Call(_) | Statement::Ret => Flow::Dynamic,
+        _ => Flow::Next,
+    }
+}
+
+fn reads_zero(value: &Value) -> bool {
+    match value {
+        Value::Register(n) => n.is_zero(),
+        // The second read goes to a register only known at run time.
+        Value::Pointer(_) => true,
+        _ => false,
+    }
+}
+
+// Whether the instruction may read register 0, counting any register only
+// known at run time as register 0. Instructions that leave the linear
+// stream count as reads too: `halt` returns register 0 and the others may
+// lead to code that reads it.
+fn may_read_zero(statement: &Statement) -> bool {
+    match statement {
+        Statement::Incr(Index::Direct(n), v) => n.is_zero() || reads_zero(v),
+        Statement::Save(Index::Direct(_), v) => reads_zero(v),
+        Statement::Save(Index::Indirect(n), v) => n.is_zero() || reads_zero(v),
+        Statement::Putc(v) | Statement::Puth(v) => reads_zero(v),
+        Statement::Putn(v, base) => reads_zero(v) || reads_zero(base),
+        Statement::Puts(Index::Direct(n)) => !n.is_positive(),
+        Statement::Gets(Index::Direct(_)) | Statement::Savepc(Index::Direct(_)) => false,
+        Statement::Nop => false,
+        _ => true,
+    }
+}
+
+impl Program {
+    /// Stores to register 0 that the next store overwrites, as pairs of
+    /// addresses. This is a heuristic over the instructions in address
+    /// order: it does not follow jumps, and any instruction that may jump
+    /// or read an unknown register ends the search, so it misses dead
+    /// stores rather than reporting live ones.
+    pub fn dead_stores(&self) -> Vec<(usize, usize)> {
+        let mut dead = Vec::new();
+        let mut pending = None;
+        for (pc, statement) in self.iter().enumerate() {
+            if may_read_zero(statement) {
+                pending = None;
+            }
+            if let Statement::Save(Index::Direct(n), _) | Statement::Savepc(Index::Direct(n)) =
+                statement
+            {
+                if n.is_zero() {
+                    if let Some(store) = pending {
+                        dead.push((store, pc));
+                    }
+                    pending = Some(pc);
+                }
+            }
+        }
+        dead
+    }
+
+    pub fn reachable_instructions(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.len()];
+        let mut pending = vec![0];
+        while let Some(pc) = pending.pop() {
+            if pc >= self.len() || reachable[pc] {
+                continue;
+            }
+            reachable[pc] = true;
+            match flow(&self[pc]) {
+                Flow::Next => pending.push(pc + 1),
+                Flow::Jump(target) => pending.push(target),
+                Flow::Branch(target) => {
+                    pending.push(pc + 1);
+                    pending.push(target);
+                }
+                Flow::Dynamic => return vec![true; self.len()],
+                Flow::Stop => {}
+            }
+        }
+        reachable
+    }
+
+    /// Every address a `decr` or `call` can jump to, sorted and without
+    /// duplicates. Jumps through a register are left out because their target
+    /// is only known at run time.
+    pub fn jump_targets(&self) -> Vec<usize> {
+        let mut targets: Vec<usize> = self
+            .iter()
+            .filter_map(|statement| match statement {
+                Statement::Decr(_, Address::Immediate(n), _)
+                | Statement::Call(Address::Immediate(n)) => n.to_usize(),
+                _ => None,
+            })
+            .collect();
+        targets.sort_unstable();
+        targets.dedup();
+        targets
+    }
+
+    pub fn lint(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let reachable = self.reachable_instructions();
+        for (pc, statement) in self.iter().enumerate() {
+            if !reachable[pc] {
+                diagnostics.push(Diagnostic::warning(
+                    self,
+                    Lint::Unreachable,
+                    pc,
+                    format!("Instruction at {} is unreachable", pc),
+                ));
+                continue;
+            }
+            if let Statement::Decr(_, Address::Immediate(target), _) = statement {
+                if *target == Number::from(pc) {
+                    diagnostics.push(Diagnostic::warning(
+                        self,
+                        Lint::SelfLoop,
+                        pc,
+                        format!(
+                            "Instruction at {} jumps to itself and never terminates once the jump is taken",
+                            pc
+                        ),
+                    ));
+                }
+            }
+            let falls_through = matches!(flow(statement), Flow::Next | Flow::Branch(_));
+            if falls_through && pc + 1 == self.len() {
+                diagnostics.push(Diagnostic::warning(
+                    self,
+                    Lint::FallOffEnd,
+                    pc,
+                    format!("Execution can run past the last instruction at {}", pc),
+                ));
+            }
+        }
+        let halts = self
+            .iter()
+            .zip(&reachable)
+            .any(|(statement, &reachable)| reachable && matches!(statement, Statement::Halt));
+        if !halts && !self.is_empty() {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                kind: Lint::NoReachableHalt,
+                message: String::from(
+                    "No halt can be reached; the program either loops forever or fails",
+                ),
+                line: None,
+                column: None,
+                address: None,
+            });
+        }
+        for (store, overwrite) in self.dead_stores() {
+            diagnostics.push(Diagnostic::warning(
+                self,
+                Lint::DeadStore,
+                store,
+                format!(
+                    "Register 0 set at {} is overwritten at {} before it is read",
+                    store, overwrite
+                ),
+            ));
+        }
+        for (label, pc) in self.unused_labels() {
+            diagnostics.push(Diagnostic::warning(
+                self,
+                Lint::UnusedLabel,
+                *pc,
+                format!("Label {} is defined but never used", label),
+            ));
+        }
+        diagnostics
+    }
+}
+
+/// Assembles the source and lints the result. On success the warnings come
+/// along with the program; otherwise every syntax error found, or the single
+/// compile error, is returned as an error diagnostic.
+pub fn compile(src: &str) -> Result<(Program, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let (ast, errors) = parse_ast(src);
+    if !errors.is_empty() {
+        return Err(errors.iter().map(Diagnostic::syntax).collect());
+    }
+    let program = Program::new(ast).map_err(|e| vec![Diagnostic::compile(&e)])?;
+    let diagnostics = program.lint();
+    Ok((program, diagnostics))
+}
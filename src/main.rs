@@ -1,51 +1,425 @@
-use aaron_asm::MachineState;
+use aaron_asm::{
+    buffered_stdout, mnemonics, AssembleError, FileError, MachineState, Program, RunError,
+    SyntaxError,
+};
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::IsTerminal;
 
-trait ErrorExit<T, U> {
-    fn if_error_then_exit(&self) -> &T;
+enum Failure {
+    Argument,
+    File(std::io::Error),
+    Encoding(String),
+    Parse(String),
+    Lint(usize),
+    Run(RunError),
 }
 
-impl<T, U> ErrorExit<T, U> for Result<T, U>
-where
-    U: std::fmt::Display,
-{
-    fn if_error_then_exit(&self) -> &T {
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Ok(ref a) => a,
-            Err(ref message) => {
-                eprintln!("{}", message);
-                std::process::exit(1);
+            Failure::Argument => write!(f, "Command line argument is invalid"),
+            Failure::File(e) => write!(f, "{}", e),
+            Failure::Encoding(filename) => write!(f, "{}: file is not valid UTF-8", filename),
+            Failure::Parse(message) => write!(f, "{}", message),
+            Failure::Lint(count) => write!(f, "{} warning(s) treated as errors", count),
+            Failure::Run(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<RunError> for Failure {
+    fn from(e: RunError) -> Failure {
+        Failure::Run(e)
+    }
+}
+
+/// Exit codes are part of the command line interface; keep them stable.
+///
+/// | code | failure                                   |
+/// |------|-------------------------------------------|
+/// | 1    | parse error                               |
+/// | 2    | input file can not be read                |
+/// | 3    | invalid command line argument             |
+/// | 4    | invalid program counter or jump target    |
+/// | 5    | register number exceeds the limit         |
+/// | 6    | input or output error                     |
+/// | 7    | warnings denied by `--deny-warnings`      |
+/// | 8    | syscall without a registered handler, or  |
+/// |      | loadcode without `--loadcode`             |
+/// | 9    | source file is not valid UTF-8            |
+/// | 10   | a step, output or memory limit exceeded,  |
+/// |      | or the run was cancelled                  |
+fn exit_code(failure: &Failure) -> i32 {
+    match failure {
+        Failure::Parse(_) => 1,
+        Failure::File(_) => 2,
+        Failure::Argument => 3,
+        Failure::Run(e) => run_exit_code(e),
+        Failure::Lint(_) => 7,
+        Failure::Encoding(_) => 9,
+    }
+}
+
+fn run_exit_code(error: &RunError) -> i32 {
+    match error {
+        RunError::Line(_, e) => run_exit_code(e),
+        RunError::InvalidProgramCounter(_)
+        | RunError::InvalidJumpTarget(_)
+        | RunError::StackOverflow(_)
+        | RunError::StackUnderflow => 4,
+        RunError::RegisterLimit(_) => 5,
+        RunError::InvalidOutputChar(_)
+        | RunError::InvalidRadix(_)
+        | RunError::Output(_)
+        | RunError::Input(_) => 6,
+        RunError::UnknownSyscall(_) | RunError::CodeNotReadable => 8,
+        RunError::StepLimit(_)
+        | RunError::OutputLimit(_)
+        | RunError::MemoryLimit(_)
+        | RunError::Cancelled => 10,
+    }
+}
+
+// `print!` panics when stdout is gone; this reports it as an output error.
+fn write_stdout(text: &str) -> Result<(), Failure> {
+    std::io::stdout()
+        .write_all(text.as_bytes())
+        .map_err(|e| Failure::Run(RunError::Output(e)))
+}
+
+// A reader such as `head` closed the pipe. Like other Unix tools, stop
+// quietly instead of reporting an error.
+fn is_broken_pipe(failure: &Failure) -> bool {
+    match failure {
+        Failure::Run(e) => match e.cause() {
+            RunError::Output(e) => e.kind() == std::io::ErrorKind::BrokenPipe,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn render_syntax_error(source: &str, error: &SyntaxError, color: bool) -> String {
+    let line = source.lines().nth(error.line - 1).unwrap_or("");
+    let indent: String = line
+        .chars()
+        .take(error.column - 1)
+        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+        .collect();
+    if color {
+        format!(
+            "\x1b[1;31m{}\x1b[0m\n{}\n{}\x1b[1;31m^\x1b[0m",
+            error, line, indent
+        )
+    } else {
+        format!("{}\n{}\n{}^", error, line, indent)
+    }
+}
+
+fn render_assemble_error(source: &str, error: &AssembleError) -> String {
+    match error {
+        AssembleError::Parse(e) => {
+            let color = std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+            render_syntax_error(source, e, color)
+        }
+        _ => error.to_string(),
+    }
+}
+
+fn assemble_failure(filename: &str, error: FileError) -> Failure {
+    match error {
+        FileError::Io(e) => Failure::File(e),
+        FileError::Encoding(_) => Failure::Encoding(filename.to_string()),
+        FileError::Assemble { source, error } => {
+            Failure::Parse(render_assemble_error(&source, &error))
+        }
+    }
+}
+
+// An instruction typed at the prompt doesn't need the indentation a source
+// file requires; anything else starts with a label.
+fn repl_source_line(line: &str) -> String {
+    let word = line.split_whitespace().next().unwrap_or("");
+    if mnemonics().iter().any(|info| info.name == word) {
+        format!("        {}", line.trim_start())
+    } else {
+        line.to_string()
+    }
+}
+
+/// Reads instructions one line at a time and runs each against a persistent
+/// machine. Every line is appended to the program so that labels and jumps
+/// can refer to earlier lines. Execution continues until the program counter
+/// leaves the program or the machine halts.
+fn repl<R: BufRead, W: Write>(input: R, output: &mut W) -> std::io::Result<()> {
+    let mut source = String::new();
+    let mut buffer = Vec::new();
+    let mut machine = MachineState::new(&mut buffer);
+    for line in input.lines() {
+        let line = line?;
+        match line.trim() {
+            "" => continue,
+            ":quit" => break,
+            ":regs" => {
+                writeln!(output, "{:?}", machine)?;
+                continue;
+            }
+            ":reset" => {
+                source.clear();
+                machine.reset();
+                writeln!(output, "reset")?;
+                continue;
             }
+            _ => {}
         }
+        let start = source.parse::<Program>().map_or(0, |program| program.len());
+        let candidate = format!("{}{}\n", source, repl_source_line(&line));
+        let program = match candidate.parse::<Program>() {
+            Ok(program) => program,
+            Err(e) => {
+                writeln!(output, "error: {}", e)?;
+                continue;
+            }
+        };
+        source = candidate;
+        machine.set_program_counter(start);
+        let mut halted = false;
+        let mut failure = None;
+        while machine.program_counter() < &program.len().into() {
+            match machine.step(&program) {
+                Ok(true) => {
+                    halted = true;
+                    break;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+        output.write_all(&std::mem::take(machine.output_mut()))?;
+        if let Some(e) = failure {
+            writeln!(output, "error: {}", e)?;
+        }
+        writeln!(
+            output,
+            "r0 = {}, pc = {}{}",
+            machine.read_register(0),
+            machine.program_counter(),
+            if halted { " (halted)" } else { "" }
+        )?;
     }
+    Ok(())
 }
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let mut arg_p: usize = 1;
+enum Emit {
+    Text,
+    Bytecode,
+}
+
+fn execute() -> Result<(), Failure> {
     let mut compile_only = false;
-    if args.len() > 1 {
-        if args[arg_p] == "-c" {
-            compile_only = true;
-            arg_p += 1;
-        }
-        let file = File::open(&args[arg_p]);
-        let mut file = file.if_error_then_exit();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        let program = contents.parse();
-        let program = program.if_error_then_exit();
-        if compile_only {
-            print!("{}", program);
-        } else {
-            let stdout = std::io::stdout();
-            let mut handle = stdout.lock();
-            let mut machine = MachineState::new(&mut handle);
-            println!("{}", machine.run(&program));
+    let mut emit = Emit::Text;
+    let mut emit_ast = false;
+    let mut profile = false;
+    let mut bench = false;
+    let mut bytecode = false;
+    let mut warnings = false;
+    let mut deny_warnings = false;
+    let mut quiet = false;
+    let mut group = None;
+    let mut unbuffered = false;
+    let mut format = false;
+    let mut format_options = aaron_asm::FormatOptions::default();
+    let mut loadcode = false;
+    let mut max_steps = None;
+    let mut filename = None;
+    for arg in std::env::args().skip(1) {
+        match &arg[..] {
+            "--repl" => {
+                let stdin = std::io::stdin();
+                let stdout = std::io::stdout();
+                return repl(stdin.lock(), &mut stdout.lock())
+                    .map_err(|e| Failure::Run(RunError::Output(e)));
+            }
+            "-c" => compile_only = true,
+            "-b" | "--emit=bytecode" => emit = Emit::Bytecode,
+            "--emit=text" => emit = Emit::Text,
+            "--emit=ast" => emit_ast = true,
+            _ if arg.starts_with("--emit=") => return Err(Failure::Argument),
+            "--profile" => profile = true,
+            "--bench" => bench = true,
+            "--bytecode" => bytecode = true,
+            "--warnings" => warnings = true,
+            "--deny-warnings" => deny_warnings = true,
+            "-q" | "--quiet" => quiet = true,
+            "--unbuffered" => unbuffered = true,
+            "--fmt" => format = true,
+            _ if arg.starts_with("--label-gap=") => {
+                let gap = arg["--label-gap=".len()..]
+                    .parse()
+                    .map_err(|_| Failure::Argument)?;
+                format_options.label_gap = Some(gap);
+            }
+            "--loadcode" => loadcode = true,
+            _ if arg.starts_with("--max-steps=") => {
+                let steps = arg["--max-steps=".len()..]
+                    .parse()
+                    .map_err(|_| Failure::Argument)?;
+                max_steps = Some(steps);
+            }
+            "--group" => group = Some(','),
+            _ if arg.starts_with("--group=") => {
+                let mut chars = arg["--group=".len()..].chars();
+                match (chars.next(), chars.next()) {
+                    (Some(separator), None) => group = Some(separator),
+                    _ => return Err(Failure::Argument),
+                }
+            }
+            // An unknown option or a second file is a mistake, not a file name.
+            _ if arg.starts_with('-') || filename.is_some() => return Err(Failure::Argument),
+            _ => filename = Some(arg),
         }
+    }
+    let filename = filename.ok_or(Failure::Argument)?;
+    // Bytecode has no syntax tree to show.
+    if bytecode && emit_ast {
+        return Err(Failure::Argument);
+    }
+    let program = if bytecode {
+        let mut contents = Vec::new();
+        File::open(&filename)
+            .and_then(|mut file| file.read_to_end(&mut contents))
+            .map_err(Failure::File)?;
+        Program::from_bytecode(&contents).map_err(|e| Failure::Parse(e.to_string()))?
+    } else if format {
+        let contents =
+            aaron_asm::read_source(&filename).map_err(|e| assemble_failure(&filename, e))?;
+        let formatted = aaron_asm::format_source_with(&contents, &format_options).map_err(|e| {
+            Failure::Parse(render_assemble_error(&contents, &AssembleError::Parse(e)))
+        })?;
+        write_stdout(&formatted)?;
+        return Ok(());
+    } else if emit_ast {
+        let contents =
+            aaron_asm::read_source(&filename).map_err(|e| assemble_failure(&filename, e))?;
+        let (ast, mut errors) = aaron_asm::parse_ast(&contents);
+        if !errors.is_empty() {
+            let error = AssembleError::Parse(errors.remove(0));
+            return Err(Failure::Parse(render_assemble_error(&contents, &error)));
+        }
+        write_stdout(&format!("{:#?}\n", ast))?;
+        return Ok(());
     } else {
-        eprintln!("Command line argument is invalid");
-        std::process::exit(3);
+        aaron_asm::compile_file(&filename).map_err(|e| assemble_failure(&filename, e))?
+    };
+    if warnings || deny_warnings {
+        let diagnostics = program.lint();
+        for diagnostic in &diagnostics {
+            eprintln!("{}", diagnostic);
+        }
+        if deny_warnings && !diagnostics.is_empty() {
+            return Err(Failure::Lint(diagnostics.len()));
+        }
+    }
+    if compile_only {
+        match emit {
+            Emit::Text => write_stdout(&program.to_string())?,
+            Emit::Bytecode => std::io::stdout()
+                .write_all(&program.to_bytecode())
+                .map_err(|e| Failure::Run(RunError::Output(e)))?,
+        }
+    } else {
+        let mut handle = buffered_stdout();
+        let mut machine = MachineState::new(&mut handle);
+        let stdin = std::io::stdin();
+        let mut input = stdin.lock();
+        machine.set_input(&mut input);
+        machine.set_digit_grouping(group);
+        machine.set_unbuffered(unbuffered);
+        machine.set_code_readable(loadcode);
+        machine.set_max_steps(max_steps);
+        if bench {
+            let (_, throughput) = machine.run_with_throughput(&program)?;
+            eprint!("{}", throughput);
+        } else if profile {
+            let (result, stats) = machine.run_with_stats(&program)?;
+            if !quiet {
+                write_stdout(&format!("{}\n", result))?;
+            }
+            eprint!("{}", stats);
+        } else {
+            let result = machine.run(&program)?;
+            if !quiet {
+                write_stdout(&format!("{}\n", result))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(failure) = execute() {
+        if is_broken_pipe(&failure) {
+            return;
+        }
+        eprintln!("{}", failure);
+        std::process::exit(exit_code(&failure));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_syntax_error, repl};
+    use aaron_asm::{AssembleError, Program};
+
+    #[test]
+    fn caret_position_test() {
+        let source = "        save 0, 1\n        incr 0, [1\n        halt\n";
+        let error = match source.parse::<Program>() {
+            Err(AssembleError::Parse(e)) => e,
+            _ => panic!("expected a syntax error"),
+        };
+        let rendered = render_syntax_error(source, &error, false);
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines[1], "        incr 0, [1");
+        assert_eq!(lines[2], "                  ^");
+    }
+
+    #[test]
+    fn repl_test() {
+        let script = "incr 0, 2
+save 1, 3
+:regs
+loop decr 1, 5
+incr 0
+decr -1, loop
+putn [0]
+jump 0
+:reset
+incr 0
+:quit
+incr 0
+";
+        let mut output = Vec::new();
+        repl(script.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "r0 = 2, pc = 1");
+        assert_eq!(lines[1], "r0 = 2, pc = 2");
+        assert_eq!(
+            lines[2],
+            "MachineState { program_counter: 2, registers: {0: 2, 1: 3} }"
+        );
+        assert_eq!(lines[3], "r0 = 2, pc = 3");
+        assert_eq!(lines[4], "r0 = 3, pc = 4");
+        assert_eq!(lines[5], "r0 = 5, pc = 5");
+        assert_eq!(lines[6], "5r0 = 5, pc = 6");
+        assert!(lines[7].starts_with("error: "));
+        assert_eq!(lines[8], "reset");
+        assert_eq!(lines[9], "r0 = 1, pc = 1");
+        assert_eq!(lines.len(), 10);
     }
 }
@@ -0,0 +1,111 @@
+use crate::compiler::AssembleError;
+use crate::syntax_tree::Program;
+use crate::vm::{MachineBuilder, RunError};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub timeout: Duration,
+    pub max_steps: Option<u64>,
+    /// The most characters the program may print.
+    pub max_output: Option<usize>,
+    /// The most registers the program may allocate, counted as
+    /// `MachineState::allocated_registers` does.
+    pub max_registers: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            timeout: Duration::from_secs(1),
+            max_steps: None,
+            max_output: None,
+            max_registers: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SandboxError {
+    Assemble(AssembleError),
+    Run(RunError),
+    Timeout,
+    StepLimit,
+    OutputLimit,
+    MemoryLimit,
+    Panic,
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SandboxError::Assemble(e) => write!(f, "{}", e),
+            SandboxError::Run(e) => write!(f, "{}", e),
+            SandboxError::Timeout => write!(f, "Time limit exceeded"),
+            SandboxError::StepLimit => write!(f, "Step limit exceeded"),
+            SandboxError::OutputLimit => write!(f, "Output limit exceeded"),
+            SandboxError::MemoryLimit => write!(f, "Memory limit exceeded"),
+            SandboxError::Panic => write!(f, "Virtual machine panicked"),
+        }
+    }
+}
+
+impl std::error::Error for SandboxError {}
+
+fn run_limited(
+    program: &Program,
+    limits: &Limits,
+    cancelled: &AtomicBool,
+) -> Result<String, SandboxError> {
+    let mut builder = MachineBuilder::new();
+    if let Some(max) = limits.max_steps {
+        builder = builder.max_steps(max);
+    }
+    if let Some(max) = limits.max_output {
+        builder = builder.max_output(max);
+    }
+    if let Some(max) = limits.max_registers {
+        builder = builder.max_registers(max);
+    }
+    let mut buffer = Vec::new();
+    let mut machine = builder.build(&mut buffer);
+    if let Err(e) = machine.run_cancellable(program, cancelled) {
+        return Err(match e.cause() {
+            RunError::Cancelled => SandboxError::Timeout,
+            RunError::StepLimit(_) => SandboxError::StepLimit,
+            RunError::OutputLimit(_) => SandboxError::OutputLimit,
+            RunError::MemoryLimit(_) => SandboxError::MemoryLimit,
+            _ => SandboxError::Run(e),
+        });
+    }
+    drop(machine);
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+/// Assembles and runs an untrusted program on a worker thread. The worker is
+/// told to stop once the time limit passes, and the caller gets `Timeout`
+/// without waiting for it. Assembling counts against the time limit too.
+pub fn run_sandboxed(src: &str, limits: Limits) -> Result<String, SandboxError> {
+    let src = src.to_string();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = mpsc::channel();
+    let worker_cancelled = Arc::clone(&cancelled);
+    std::thread::spawn(move || {
+        let result = src
+            .parse()
+            .map_err(SandboxError::Assemble)
+            .and_then(|program| run_limited(&program, &limits, &worker_cancelled));
+        let _ = sender.send(result);
+    });
+    match receiver.recv_timeout(limits.timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            cancelled.store(true, Ordering::Relaxed);
+            Err(SandboxError::Timeout)
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(SandboxError::Panic),
+    }
+}
@@ -2,13 +2,75 @@ extern crate num_bigint;
 extern crate num_traits;
 pub type Number = num_bigint::BigInt;
 
+use num_traits::ToPrimitive;
 use std::fmt;
 use std::option::Option;
 
+#[derive(Debug)]
+pub enum CompileError {
+    UnknownLabel(String, usize),
+    InvalidCharacter(Number, usize),
+    NegativeRegister(Number, usize),
+    RegisterLimit(Number, usize),
+    ConstantAsAddress(String, usize),
+    /// A label defined again, with the line of the first definition and of
+    /// the second.
+    DuplicateLabel(String, usize, usize),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line(), self.message())
+    }
+}
+
+impl CompileError {
+    /// The error without the line it is on.
+    pub fn message(&self) -> String {
+        match self {
+            CompileError::UnknownLabel(label, _) => format!("Unknown label {}", label),
+            CompileError::InvalidCharacter(n, _) => format!("Invalid character code {}", n),
+            CompileError::NegativeRegister(n, _) => format!("Negative register number {}", n),
+            CompileError::RegisterLimit(n, _) => format!("Too big register number {}", n),
+            CompileError::ConstantAsAddress(name, _) => format!(
+                "{} is a constant, not a label, and can not be a jump target",
+                name
+            ),
+            CompileError::DuplicateLabel(label, first, _) => {
+                format!("Label {} is already defined at line {}", label, first)
+            }
+        }
+    }
+
+    pub fn line(&self) -> usize {
+        match self {
+            CompileError::UnknownLabel(_, line)
+            | CompileError::InvalidCharacter(_, line)
+            | CompileError::NegativeRegister(_, line)
+            | CompileError::RegisterLimit(_, line)
+            | CompileError::ConstantAsAddress(_, line)
+            | CompileError::DuplicateLabel(_, _, line) => *line,
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+// `BigInt`'s own `Debug` spells out its limbs; the syntax tree dumps show
+// numbers in decimal instead.
+struct Decimal<'a>(&'a Number);
+
+impl<'a> fmt::Debug for Decimal<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 #[derive(Clone)]
 pub enum Index {
     Direct(Number),
     Indirect(Number),
+    Pointer(Number),
 }
 
 impl fmt::Display for Index {
@@ -16,10 +78,22 @@ impl fmt::Display for Index {
         match self {
             Index::Direct(ref n) => write!(f, "{}", n),
             Index::Indirect(ref n) => write!(f, "[{}]", n),
+            Index::Pointer(ref n) => write!(f, "[[{}]]", n),
         }
     }
 }
 
+impl fmt::Debug for Index {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, n) = match self {
+            Index::Direct(n) => ("Direct", n),
+            Index::Indirect(n) => ("Indirect", n),
+            Index::Pointer(n) => ("Pointer", n),
+        };
+        f.debug_tuple(name).field(&Decimal(n)).finish()
+    }
+}
+
 #[derive(Clone)]
 pub enum Value {
     Immediate(Number),
@@ -41,20 +115,33 @@ impl fmt::Display for Value {
     }
 }
 
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Immediate(n) => f.debug_tuple("Immediate").field(&Decimal(n)).finish(),
+            Value::Register(n) => f.debug_tuple("Register").field(&Decimal(n)).finish(),
+            Value::Pointer(n) => f.debug_tuple("Pointer").field(&Decimal(n)).finish(),
+            Value::Label(label) => f.debug_tuple("Label").field(label).finish(),
+            Value::ProgramCounter => write!(f, "ProgramCounter"),
+        }
+    }
+}
+
 impl Value {
     fn solve(&self, labels: &HashMap<&String, Number>, pc: usize) -> Option<Value> {
         match self {
-            Value::Label(ref n) => {
-                if let Some(a) = labels.get(&n) {
-                    Some(Value::Immediate(a.clone()))
-                } else {
-                    None
-                }
-            }
+            Value::Label(ref n) => labels.get(&n).map(|a| Value::Immediate(a.clone())),
             Value::ProgramCounter => Some(Value::Immediate(Number::from(pc + 1))),
             _ => Some(self.clone()),
         }
     }
+
+    fn label(&self) -> Option<&String> {
+        match self {
+            Value::Label(ref n) => Some(n),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -76,30 +163,94 @@ impl fmt::Display for Address {
     }
 }
 
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Immediate(n) => f.debug_tuple("Immediate").field(&Decimal(n)).finish(),
+            Address::Register(n) => f.debug_tuple("Register").field(&Decimal(n)).finish(),
+            Address::Label(label) => f.debug_tuple("Label").field(label).finish(),
+            Address::ProgramCounter => write!(f, "ProgramCounter"),
+        }
+    }
+}
+
 impl Address {
     fn solve(&self, labels: &HashMap<&String, Number>, pc: usize) -> Option<Address> {
         match self {
-            Address::Label(ref n) => {
-                if let Some(a) = labels.get(&n) {
-                    Some(Address::Immediate(a.clone()))
-                } else {
-                    None
-                }
-            }
+            Address::Label(ref n) => labels.get(&n).map(|a| Address::Immediate(a.clone())),
             Address::ProgramCounter => Some(Address::Immediate(Number::from(pc + 1))),
             _ => Some(self.clone()),
         }
     }
+
+    fn label(&self) -> Option<&String> {
+        match self {
+            Address::Label(ref n) => Some(n),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub enum Statement {
+    /// Adds the value to the register. A negative index makes the instruction
+    /// a no-op, while a negative value subtracts and may leave the register
+    /// below zero.
     Incr(Index, Value),
     Decr(Index, Address, Value),
+    /// Subtracts the value without jumping. If the register would go below
+    /// zero it is set to zero and the second register, the borrow, to 1;
+    /// otherwise the borrow is set to 0.
+    Decrb(Index, Index, Value),
     Save(Index, Value),
+    /// Stores the address of this instruction, read at run time. Unlike the
+    /// `pc` operand it is not the address of the next instruction.
+    Savepc(Index),
+    /// Stores the code of the instruction at the address, as given by its
+    /// bytecode. Only runs on a machine that allows reading code.
+    Loadcode(Index, Address),
     Putc(Value),
-    Putn(Value),
+    /// Prints the value in the base given by the second operand, which must
+    /// be between 2 and 36.
+    Putn(Value, Value),
+    Puth(Value),
+    Puts(Index),
+    Gets(Index),
+    /// Copies one character from input to output, or halts at the end of
+    /// input.
+    Echo,
     Halt,
+    Nop,
+    /// Calls the host handler registered for the number.
+    Ext(Number),
+    /// Pushes the address of the next instruction and jumps.
+    Call(Address),
+    /// Jumps to the address pushed by the latest `call`.
+    Ret,
+}
+
+impl Statement {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Statement::Incr(_, _) => "incr",
+            Statement::Decr(_, _, _) => "decr",
+            Statement::Decrb(_, _, _) => "decrb",
+            Statement::Save(_, _) => "save",
+            Statement::Savepc(_) => "savepc",
+            Statement::Loadcode(_, _) => "loadcode",
+            Statement::Putc(_) => "putc",
+            Statement::Putn(_, _) => "putn",
+            Statement::Puth(_) => "puth",
+            Statement::Puts(_) => "puts",
+            Statement::Gets(_) => "gets",
+            Statement::Echo => "echo",
+            Statement::Halt => "halt",
+            Statement::Nop => "nop",
+            Statement::Call(_) => "call",
+            Statement::Ret => "ret",
+            Statement::Ext(_) => "syscall",
+        }
+    }
 }
 
 impl fmt::Display for Statement {
@@ -107,50 +258,336 @@ impl fmt::Display for Statement {
         match self {
             Statement::Incr(ref i, ref v) => write!(f, "incr {}, {}", i, v),
             Statement::Decr(ref i, ref a, ref v) => write!(f, "decr {}, {}, {}", i, a, v),
+            Statement::Decrb(ref i, ref b, ref v) => write!(f, "decrb {}, {}, {}", i, b, v),
             Statement::Save(ref i, ref v) => write!(f, "save {}, {}", i, v),
+            Statement::Savepc(ref i) => write!(f, "savepc {}", i),
+            Statement::Loadcode(ref i, ref a) => write!(f, "loadcode {}, {}", i, a),
             Statement::Putc(ref v) => write!(f, "putc {}", v),
-            Statement::Putn(ref v) => write!(f, "putn {}", v),
+            Statement::Putn(ref v, Value::Immediate(ref base)) if *base == Number::from(10) => {
+                write!(f, "putn {}", v)
+            }
+            Statement::Putn(ref v, ref base) => write!(f, "putn {}, {}", v, base),
+            Statement::Puth(ref v) => write!(f, "puth {}", v),
+            Statement::Puts(ref i) => write!(f, "puts {}", i),
+            Statement::Gets(ref i) => write!(f, "gets {}", i),
+            Statement::Echo => write!(f, "echo"),
             Statement::Halt => write!(f, "halt"),
+            Statement::Nop => write!(f, "nop"),
+            Statement::Call(ref a) => write!(f, "call {}", a),
+            Statement::Ret => write!(f, "ret"),
+            Statement::Ext(ref n) => write!(f, "syscall {}", n),
         }
     }
 }
 
+impl fmt::Debug for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Statement::Incr(i, v) => f.debug_tuple("Incr").field(i).field(v).finish(),
+            Statement::Decr(i, a, v) => f.debug_tuple("Decr").field(i).field(a).field(v).finish(),
+            Statement::Decrb(i, b, v) => f.debug_tuple("Decrb").field(i).field(b).field(v).finish(),
+            Statement::Save(i, v) => f.debug_tuple("Save").field(i).field(v).finish(),
+            Statement::Savepc(i) => f.debug_tuple("Savepc").field(i).finish(),
+            Statement::Loadcode(i, a) => f.debug_tuple("Loadcode").field(i).field(a).finish(),
+            Statement::Putc(v) => f.debug_tuple("Putc").field(v).finish(),
+            Statement::Putn(v, b) => f.debug_tuple("Putn").field(v).field(b).finish(),
+            Statement::Puth(v) => f.debug_tuple("Puth").field(v).finish(),
+            Statement::Puts(i) => f.debug_tuple("Puts").field(i).finish(),
+            Statement::Gets(i) => f.debug_tuple("Gets").field(i).finish(),
+            Statement::Halt => write!(f, "Halt"),
+            Statement::Echo => write!(f, "Echo"),
+            Statement::Nop => write!(f, "Nop"),
+            Statement::Call(a) => f.debug_tuple("Call").field(a).finish(),
+            Statement::Ret => write!(f, "Ret"),
+            Statement::Ext(n) => f.debug_tuple("Ext").field(&Decimal(n)).finish(),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct Line {
     label: Option<String>,
     statement: Statement,
+    line: usize,
 }
 
 impl Line {
-    pub fn new(label: Option<String>, statement: Statement) -> Line {
-        Line { label, statement }
+    pub fn new(label: Option<String>, statement: Statement, line: usize) -> Line {
+        Line {
+            label,
+            statement,
+            line,
+        }
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn statement(&self) -> &Statement {
+        &self.statement
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+/// A label with the address it names. A label on a line whose instructions
+/// are all `align` padding, or that has none, names the address after the
+/// line.
+#[derive(Debug)]
+pub struct Label {
+    pub name: String,
+    pub address: usize,
+    pub line: usize,
+}
+
+/// A comment and where it starts. The text includes its `;` or `//`.
+#[derive(Debug)]
+pub struct Comment {
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+}
+
+/// A line holding instructions or a directive as it was written, for the
+/// formatter. A `times`, `align` or `:` line stays one line with one comment
+/// however many instructions it stands for.
+#[derive(Debug)]
+pub struct SourceLine {
+    pub line: usize,
+    /// The label, or the name an `equ` defines.
+    pub label: Option<String>,
+    /// Everything after the label and before the comment, with operands and
+    /// directives spelled as in the source and only the spacing evened out.
+    pub text: String,
+    pub comment: Option<Comment>,
+}
+
+/// A `data` directive: values for consecutive registers starting at `index`,
+/// stored before the first instruction runs.
+pub struct Data {
+    pub index: Number,
+    pub values: Vec<Value>,
+    pub line: usize,
+}
+
+impl Data {
+    pub fn new(index: Number, values: Vec<Value>, line: usize) -> Data {
+        Data {
+            index,
+            values,
+            line,
+        }
+    }
+}
+
+impl fmt::Debug for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Data")
+            .field("index", &Decimal(&self.index))
+            .field("values", &self.values)
+            .field("line", &self.line)
+            .finish()
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "data {}", self.index)?;
+        for value in &self.values {
+            write!(f, ", {}", value)?;
+        }
+        Ok(())
     }
 }
 
-pub struct Ast(pub Vec<Line>);
+/// A number named by `NAME equ VALUE`.
+pub struct Constant {
+    pub name: String,
+    pub value: Number,
+    pub line: usize,
+}
+
+impl fmt::Debug for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Constant")
+            .field("name", &self.name)
+            .field("value", &Decimal(&self.value))
+            .field("line", &self.line)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct Ast {
+    lines: Vec<Line>,
+    labels: Vec<Label>,
+    sources: Vec<SourceLine>,
+    comments: Vec<Comment>,
+    data: Vec<Data>,
+    constants: Vec<Constant>,
+}
 
+use crate::dispatch::Op;
 use std::ops::{Deref, DerefMut};
+use std::sync::OnceLock;
 
 impl Deref for Ast {
     type Target = Vec<Line>;
     fn deref(&self) -> &Vec<Line> {
-        &self.0
+        &self.lines
     }
 }
 
+impl Ast {
+    pub fn new(lines: Vec<Line>, comments: Vec<Comment>) -> Ast {
+        let labels = lines
+            .iter()
+            .enumerate()
+            .filter_map(|(address, x)| {
+                x.label.as_ref().map(|name| Label {
+                    name: name.clone(),
+                    address,
+                    line: x.line,
+                })
+            })
+            .collect();
+        let sources = lines
+            .iter()
+            .map(|x| SourceLine {
+                line: x.line,
+                label: x.label.clone(),
+                text: x.statement.to_string(),
+                comment: None,
+            })
+            .collect();
+        Ast {
+            lines,
+            labels,
+            sources,
+            comments,
+            data: Vec::new(),
+            constants: Vec::new(),
+        }
+    }
+
+    pub(crate) fn set_labels(&mut self, labels: Vec<Label>) {
+        self.labels = labels;
+    }
+
+    pub(crate) fn set_sources(&mut self, sources: Vec<SourceLine>) {
+        self.sources = sources;
+    }
+
+    pub(crate) fn set_data(&mut self, data: Vec<Data>) {
+        self.data = data;
+    }
+
+    pub(crate) fn set_constants(&mut self, constants: Vec<Constant>) {
+        self.constants = constants;
+    }
+
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+
+    pub fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    pub fn sources(&self) -> &[SourceLine] {
+        &self.sources
+    }
+
+    /// The comments on lines of their own.
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    pub fn data(&self) -> &[Data] {
+        &self.data
+    }
+
+    /// The source again, one line for each line written, with labels in their
+    /// own column, even spacing and every comment kept. Unlike `Display` it
+    /// keeps `times`, `align`, `:`, literals and omitted operands as written.
+    pub fn format(&self) -> String {
+        self.format_with(&FormatOptions::default())
+    }
+
+    /// Like `format`, with the label column laid out as the options say.
+    pub fn format_with(&self, options: &FormatOptions) -> String {
+        let width = self
+            .sources
+            .iter()
+            .filter_map(|source| source.label.as_ref())
+            .map(|label| label.chars().count())
+            .max()
+            .unwrap_or(0);
+        // A label followed by whatever takes the statement to its column.
+        let column = |label: &str| match options.label_gap {
+            Some(gap) => format!(
+                "{}{:pad$}",
+                label,
+                "",
+                pad = width - label.chars().count() + gap
+            ),
+            None => format!("{}\t", label),
+        };
+        let mut lines: Vec<(usize, String)> = self
+            .comments
+            .iter()
+            .map(|comment| {
+                let indent = if comment.column > 1 {
+                    column("")
+                } else {
+                    String::new()
+                };
+                (comment.line, format!("{}{}", indent, comment.text))
+            })
+            .chain(self.sources.iter().map(|source| {
+                let label = source.label.as_deref().unwrap_or("");
+                let mut text = format!("{}{}", column(label), source.text);
+                if let Some(comment) = &source.comment {
+                    text.push('\t');
+                    text.push_str(&comment.text);
+                }
+                (source.line, text)
+            }))
+            .collect();
+        lines.sort_by_key(|(line, _)| *line);
+        let mut output = String::new();
+        for (_, text) in lines {
+            output.push_str(&text);
+            output.push('\n');
+        }
+        output
+    }
+}
+
+/// How `Ast::format_with` lays out the label column.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FormatOptions {
+    /// Spaces between the longest label and the statements, so that every
+    /// statement starts in the same column. With `None` each label is
+    /// followed by a single tab, as in `Display`.
+    pub label_gap: Option<usize>,
+}
+
 impl fmt::Display for Ast {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for x in self.iter() {
-            if let Err(e) = write!(
+            writeln!(
                 f,
-                "{}\t{}\n",
+                "{}\t{}",
                 match &x.label {
                     Some(label) => &label[..],
                     None => "",
                 },
                 x.statement
-            ) {
-                return Err(e);
-            }
+            )?;
         }
         Ok(())
     }
@@ -159,72 +596,371 @@ impl fmt::Display for Ast {
 use std::collections::HashMap;
 
 impl<'a> Ast {
-    fn collect_labels(&'a self) -> HashMap<&'a String, Number> {
+    // Every label with the address it names. A label defined twice is an
+    // error pointing at both definitions.
+    fn collect_labels(&'a self) -> Result<HashMap<&'a String, Number>, CompileError> {
         let mut h = HashMap::new();
-        for (
-            i,
-            &Line {
-                ref label,
-                statement: _,
-            },
-        ) in self.iter().enumerate()
+        let mut defined_at = HashMap::new();
+        for Label {
+            name,
+            address,
+            line,
+        } in &self.labels
         {
-            if let Some(ref label) = label {
-                h.insert(label, Number::from(i));
+            if let Some(first) = defined_at.insert(name, *line) {
+                return Err(CompileError::DuplicateLabel(name.clone(), first, *line));
             }
+            h.insert(name, Number::from(*address));
         }
-        h
+        Ok(h)
     }
 }
 
-pub struct Program(Vec<Statement>);
+pub struct Program {
+    statements: Vec<Statement>,
+    lines: Vec<usize>,
+    // Labels that no operand refers to, with the address they name.
+    unused_labels: Vec<(String, usize)>,
+    data: Vec<(usize, Vec<Number>)>,
+    // The statements prepared for `MachineState::run`. Changing the
+    // statements through `DerefMut` drops them, and they are prepared again
+    // on the next run.
+    ops: OnceLock<Vec<Op>>,
+}
+
+fn compile_ops(statements: &[Statement]) -> Vec<Op> {
+    statements.iter().map(Op::compile).collect()
+}
 
 impl Deref for Program {
     type Target = Vec<Statement>;
     fn deref(&self) -> &Vec<Statement> {
-        &self.0
+        &self.statements
     }
 }
 
 impl DerefMut for Program {
     fn deref_mut(&mut self) -> &mut Vec<Statement> {
-        &mut self.0
+        self.ops = OnceLock::new();
+        &mut self.statements
     }
 }
 
 impl fmt::Display for Program {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for x in self.iter() {
-            if let Err(e) = write!(f, "{}\n", x) {
-                return Err(e);
+        for (index, values) in &self.data {
+            write!(f, "data {}", index)?;
+            for value in values {
+                write!(f, ", {}", value)?;
             }
+            writeln!(f)?;
+        }
+        for x in self.iter() {
+            writeln!(f, "{}", x)?;
         }
         Ok(())
     }
 }
 
+// A register number in brackets must not be negative. Reading a negative
+// register yields zero, so `[-1]` would silently stand for register 0. A bare
+// negative index is still allowed: `decr -1, label` is the idiom for an
+// unconditional jump.
+fn negative_register(statement: &Statement) -> Option<&Number> {
+    let (indices, values, address): (Vec<&Index>, Vec<&Value>, Option<&Address>) = match statement {
+        Statement::Incr(i, v) | Statement::Save(i, v) => (vec![i], vec![v], None),
+        Statement::Decr(i, a, v) => (vec![i], vec![v], Some(a)),
+        Statement::Decrb(i, b, v) => (vec![i, b], vec![v], None),
+        Statement::Putn(v, base) => (vec![], vec![v, base], None),
+        Statement::Putc(v) | Statement::Puth(v) => (vec![], vec![v], None),
+        Statement::Puts(i) | Statement::Gets(i) | Statement::Savepc(i) => (vec![i], vec![], None),
+        Statement::Call(a) => (vec![], vec![], Some(a)),
+        Statement::Loadcode(i, a) => (vec![i], vec![], Some(a)),
+        Statement::Halt | Statement::Nop | Statement::Echo | Statement::Ext(_) | Statement::Ret => {
+            (vec![], vec![], None)
+        }
+    };
+    let indices = indices.into_iter().filter_map(|index| match index {
+        Index::Indirect(n) | Index::Pointer(n) => Some(n),
+        Index::Direct(_) => None,
+    });
+    let values = values.into_iter().filter_map(|value| match value {
+        Value::Register(n) | Value::Pointer(n) => Some(n),
+        _ => None,
+    });
+    let address = address.and_then(|address| match address {
+        Address::Register(n) => Some(n),
+        _ => None,
+    });
+    indices
+        .chain(values)
+        .chain(address)
+        .find(|n| n.sign() == num_bigint::Sign::Minus)
+}
+
+// A negative direct index names no register in any memory mode, so `save`,
+// `savepc`, `loadcode`, `gets` and `decrb`, which always write, can't use one.
+fn negative_write(statement: &Statement) -> Option<&Number> {
+    let indices = match statement {
+        Statement::Save(Index::Direct(n), _)
+        | Statement::Savepc(Index::Direct(n))
+        | Statement::Loadcode(Index::Direct(n), _)
+        | Statement::Gets(Index::Direct(n)) => vec![n],
+        Statement::Decrb(i, b, _) => vec![i, b]
+            .into_iter()
+            .filter_map(|index| match index {
+                Index::Direct(n) => Some(n),
+                _ => None,
+            })
+            .collect(),
+        _ => return None,
+    };
+    indices
+        .into_iter()
+        .find(|n| n.sign() == num_bigint::Sign::Minus)
+}
+
 impl Program {
-    pub fn new(ast: Ast) -> Option<Program> {
-        let labels = ast.collect_labels();
+    pub fn new(ast: Ast) -> Result<Program, CompileError> {
+        let labels = ast.collect_labels()?;
+        // Values may name a constant as well as a code label, addresses only
+        // a code label.
+        let mut symbols = labels.clone();
+        for constant in &ast.constants {
+            symbols.insert(&constant.name, constant.value.clone());
+        }
         let mut program = Vec::<Statement>::new();
+        let lines = ast.iter().map(|x| x.line).collect();
         for (pc, x) in ast.iter().enumerate() {
+            let solve_value = |value: &Value| {
+                value.solve(&symbols, pc).ok_or_else(|| {
+                    CompileError::UnknownLabel(value.label().cloned().unwrap_or_default(), x.line)
+                })
+            };
+            let solve_address = |address: &Address| {
+                if let Some(name) = address.label() {
+                    if !labels.contains_key(name) && symbols.contains_key(name) {
+                        return Err(CompileError::ConstantAsAddress(name.clone(), x.line));
+                    }
+                }
+                address.solve(&labels, pc).ok_or_else(|| {
+                    CompileError::UnknownLabel(address.label().cloned().unwrap_or_default(), x.line)
+                })
+            };
+            if let Some(n) = negative_register(&x.statement) {
+                return Err(CompileError::NegativeRegister(n.clone(), x.line));
+            }
+            if let Some(n) = negative_write(&x.statement) {
+                return Err(CompileError::NegativeRegister(n.clone(), x.line));
+            }
             match &x.statement {
                 Statement::Decr(index, address, value) => program.push(Statement::Decr(
                     index.clone(),
-                    address.solve(&labels, pc)?.clone(),
-                    value.solve(&labels, pc)?,
+                    solve_address(address)?,
+                    solve_value(value)?,
                 )),
                 Statement::Incr(index, value) => {
-                    program.push(Statement::Incr(index.clone(), value.solve(&labels, pc)?))
+                    program.push(Statement::Incr(index.clone(), solve_value(value)?))
                 }
+                Statement::Decrb(index, borrow, value) => program.push(Statement::Decrb(
+                    index.clone(),
+                    borrow.clone(),
+                    solve_value(value)?,
+                )),
                 Statement::Save(index, value) => {
-                    program.push(Statement::Save(index.clone(), value.solve(&labels, pc)?))
+                    program.push(Statement::Save(index.clone(), solve_value(value)?))
+                }
+                Statement::Putc(value) => {
+                    let value = solve_value(value)?;
+                    if let Value::Immediate(ref n) = value {
+                        if n.to_u32().and_then(std::char::from_u32).is_none() {
+                            return Err(CompileError::InvalidCharacter(n.clone(), x.line));
+                        }
+                    }
+                    program.push(Statement::Putc(value))
+                }
+                Statement::Putn(value, base) => {
+                    program.push(Statement::Putn(solve_value(value)?, solve_value(base)?))
+                }
+                Statement::Puth(value) => program.push(Statement::Puth(solve_value(value)?)),
+                Statement::Puts(index) => program.push(Statement::Puts(index.clone())),
+                Statement::Gets(index) => program.push(Statement::Gets(index.clone())),
+                Statement::Savepc(index) => program.push(Statement::Savepc(index.clone())),
+                Statement::Loadcode(index, address) => {
+                    program.push(Statement::Loadcode(index.clone(), solve_address(address)?))
                 }
-                Statement::Putc(value) => program.push(Statement::Putc(value.solve(&labels, pc)?)),
-                Statement::Putn(value) => program.push(Statement::Putn(value.solve(&labels, pc)?)),
                 Statement::Halt => program.push(Statement::Halt),
+                Statement::Nop => program.push(Statement::Nop),
+                Statement::Echo => program.push(Statement::Echo),
+                Statement::Ext(n) => program.push(Statement::Ext(n.clone())),
+                Statement::Call(address) => program.push(Statement::Call(solve_address(address)?)),
+                Statement::Ret => program.push(Statement::Ret),
+            }
+        }
+        let used: std::collections::HashSet<&String> = ast
+            .iter()
+            .flat_map(|x| match &x.statement {
+                Statement::Decr(_, address, value) => vec![address.label(), value.label()],
+                Statement::Call(address) | Statement::Loadcode(_, address) => {
+                    vec![address.label()]
+                }
+                Statement::Incr(_, value)
+                | Statement::Save(_, value)
+                | Statement::Decrb(_, _, value) => vec![value.label()],
+                Statement::Putn(value, base) => vec![value.label(), base.label()],
+                Statement::Putc(value) | Statement::Puth(value) => vec![value.label()],
+                _ => vec![],
+            })
+            .chain(
+                ast.data
+                    .iter()
+                    .flat_map(|data| data.values.iter().map(Value::label)),
+            )
+            .flatten()
+            .collect();
+        let unused_labels = ast
+            .labels
+            .iter()
+            .filter(|label| !used.contains(&label.name))
+            .map(|label| (label.name.clone(), label.address))
+            .collect();
+        let mut data = Vec::new();
+        // A block without values, as from `data 0, ""`, stores nothing.
+        for block in ast.data.iter().filter(|block| !block.values.is_empty()) {
+            let index = block
+                .index
+                .to_usize()
+                .filter(|index| {
+                    index
+                        .checked_add(block.values.len() - 1)
+                        .is_some_and(|last| last <= crate::vm::MEMORY_LIMIT)
+                })
+                .ok_or_else(|| CompileError::RegisterLimit(block.index.clone(), block.line))?;
+            let mut values = Vec::new();
+            for value in &block.values {
+                match value.solve(&symbols, 0) {
+                    Some(Value::Immediate(n)) => values.push(n),
+                    _ => {
+                        return Err(CompileError::UnknownLabel(
+                            value.label().cloned().unwrap_or_default(),
+                            block.line,
+                        ))
+                    }
+                }
+            }
+            data.push((index, values));
+        }
+        Ok(Program {
+            ops: OnceLock::from(compile_ops(&program)),
+            statements: program,
+            lines,
+            unused_labels,
+            data,
+        })
+    }
+
+    pub(crate) fn from_parts(statements: Vec<Statement>, lines: Vec<usize>) -> Program {
+        Program {
+            ops: OnceLock::from(compile_ops(&statements)),
+            statements,
+            lines,
+            unused_labels: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    pub(crate) fn ops(&self) -> &[Op] {
+        self.ops.get_or_init(|| compile_ops(&self.statements))
+    }
+
+    pub(crate) fn set_data(&mut self, data: Vec<(usize, Vec<Number>)>) {
+        self.data = data;
+    }
+
+    /// The `data` blocks as a starting register and the values stored from
+    /// there on.
+    pub fn data(&self) -> &[(usize, Vec<Number>)] {
+        &self.data
+    }
+
+    pub(crate) fn unused_labels(&self) -> &[(String, usize)] {
+        &self.unused_labels
+    }
+
+    /// Number of instructions.
+    pub fn len(&self) -> usize {
+        self.statements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statements.is_empty()
+    }
+
+    /// Every instruction paired with its address.
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, &Statement)> {
+        self.statements.iter().enumerate()
+    }
+
+    pub fn line(&self, pc: usize) -> Option<usize> {
+        self.lines.get(pc).cloned()
+    }
+
+    /// The largest register index named directly by any operand. Returns
+    /// `None` when an indirect write, a pointer, a string operand or a syscall
+    /// means the program can reach registers that aren't known until run time.
+    pub fn max_static_register(&self) -> Option<usize> {
+        let mut max = 0;
+        let mut note = |n: &Number| {
+            if let Some(n) = n.to_usize() {
+                max = max.max(n);
+            }
+        };
+        for statement in self.iter() {
+            let (index, value, address) = match statement {
+                Statement::Incr(i, v) | Statement::Save(i, v) => (Some(i), Some(v), None),
+                Statement::Decr(i, a, v) => (Some(i), Some(v), Some(a)),
+                Statement::Decrb(i, b, v) => {
+                    match b {
+                        Index::Direct(n) => note(n),
+                        _ => return None,
+                    }
+                    (Some(i), Some(v), None)
+                }
+                Statement::Putn(_, Value::Pointer(_)) => return None,
+                Statement::Putn(v, base) => {
+                    if let Value::Register(n) = base {
+                        note(n);
+                    }
+                    (None, Some(v), None)
+                }
+                Statement::Putc(v) | Statement::Puth(v) => (None, Some(v), None),
+                Statement::Puts(_) | Statement::Gets(_) | Statement::Ext(_) => return None,
+                Statement::Call(a) => (None, None, Some(a)),
+                Statement::Savepc(i) => (Some(i), None, None),
+                Statement::Loadcode(i, a) => (Some(i), None, Some(a)),
+                Statement::Halt | Statement::Nop | Statement::Echo | Statement::Ret => {
+                    (None, None, None)
+                }
+            };
+            match index {
+                Some(Index::Direct(n)) => note(n),
+                Some(Index::Indirect(_)) | Some(Index::Pointer(_)) => return None,
+                None => {}
+            }
+            match value {
+                Some(Value::Register(n)) => note(n),
+                Some(Value::Pointer(_)) => return None,
+                _ => {}
+            }
+            if let Some(Address::Register(n)) = address {
+                note(n);
+            }
+        }
+        for (index, values) in &self.data {
+            if let Some(last) = values.len().checked_sub(1) {
+                max = max.max(index + last);
             }
         }
-        Some(Program(program))
+        Some(max)
     }
 }
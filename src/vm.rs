@@ -1,155 +1,1304 @@
 extern crate num_bigint;
 extern crate num_traits;
+use crate::dispatch::{Op, Operand};
 use crate::syntax_tree::{Address, Index, Number, Program, Statement, Value};
-use num_traits::ToPrimitive;
-const MEMORY_LIMIT: usize = 100000;
+use num_traits::{Signed, ToPrimitive};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+pub(crate) const MEMORY_LIMIT: usize = 100000;
+// Registers below this index are kept in a dense vector. Registers above it
+// are stored sparsely so that touching a far register doesn't allocate every
+// slot in between.
+const DENSE_LIMIT: usize = 1024;
+const DEFAULT_CALL_DEPTH: usize = 10000;
+// How many instructions `run_cancellable` runs between looks at the flag.
+const CANCEL_CHECK_INTERVAL: u32 = 1024;
 
-pub struct MachineState<'a, T: std::io::Write> {
+/// Sink for everything a program prints.
+pub trait Output {
+    fn write_char(&mut self, ch: char) -> std::io::Result<()>;
+    fn write_number(&mut self, n: &Number) -> std::io::Result<()>;
+
+    fn write_hex(&mut self, n: &Number) -> std::io::Result<()> {
+        for ch in n.to_str_radix(16).chars() {
+            self.write_char(ch)?;
+        }
+        Ok(())
+    }
+
+    fn write_str(&mut self, s: &str) -> std::io::Result<()> {
+        for ch in s.chars() {
+            self.write_char(ch)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: std::io::Write> Output for W {
+    fn write_char(&mut self, ch: char) -> std::io::Result<()> {
+        write!(self, "{}", ch)
+    }
+
+    fn write_number(&mut self, n: &Number) -> std::io::Result<()> {
+        write!(self, "{}", n)
+    }
+
+    fn write_hex(&mut self, n: &Number) -> std::io::Result<()> {
+        write!(self, "{}", n.to_str_radix(16))
+    }
+
+    fn write_str(&mut self, s: &str) -> std::io::Result<()> {
+        self.write_all(s.as_bytes())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::Write::flush(self)
+    }
+}
+
+/// Output that discards everything, for runs where only the result matters.
+pub struct NullOutput;
+
+impl Output for NullOutput {
+    fn write_char(&mut self, _: char) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn write_number(&mut self, _: &Number) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn write_hex(&mut self, _: &Number) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn write_str(&mut self, _: &str) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Where the machine writes: normally borrowed from the caller, but owned
+// when the caller has nothing to keep, as with `new_null`.
+enum OutputSlot<'a, T> {
+    Borrowed(&'a mut T),
+    Owned(T),
+}
+
+impl<'a, T> std::ops::Deref for OutputSlot<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            OutputSlot::Borrowed(output) => output,
+            OutputSlot::Owned(output) => output,
+        }
+    }
+}
+
+impl<'a, T> std::ops::DerefMut for OutputSlot<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self {
+            OutputSlot::Borrowed(output) => output,
+            OutputSlot::Owned(output) => output,
+        }
+    }
+}
+
+// How many characters `putn` or `puth` prints for the value.
+fn printed_len(value: &Number, radix: u32, separator: Option<char>) -> usize {
+    let digits = value.to_str_radix(radix);
+    match separator {
+        Some(separator) => group_digits(&digits, separator).chars().count(),
+        None => digits.len(),
+    }
+}
+
+// Reads one UTF-8 encoded character, or `None` at the end of input.
+fn read_char(input: &mut dyn std::io::BufRead) -> std::io::Result<Option<char>> {
+    let mut bytes = [0; 4];
+    match input.read_exact(&mut bytes[..1]) {
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let len = match bytes[0] {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        _ => 4,
+    };
+    input.read_exact(&mut bytes[1..len])?;
+    std::str::from_utf8(&bytes[..len])
+        .ok()
+        .and_then(|s| s.chars().next())
+        .map(Some)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid UTF-8"))
+}
+
+/// Inserts the separator between every three digits, counting from the right.
+fn group_digits(digits: &str, separator: char) -> String {
+    let (sign, digits) = match digits.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", digits),
+    };
+    let mut grouped = String::from(sign);
+    for (i, ch) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(separator);
+        }
+        grouped.push(ch);
+    }
+    grouped
+}
+
+pub struct MachineState<'a, T: Output> {
     registers: Vec<Number>,
+    // Which dense registers have been written, as opposed to only filled in
+    // by growing the vector.
+    written: Vec<bool>,
+    sparse_registers: HashMap<usize, Number>,
     program_counter: Number,
-    output: &'a mut T,
+    output: OutputSlot<'a, T>,
+    input: Option<&'a mut dyn std::io::BufRead>,
+    stats: Option<RunStats>,
+    syscalls: HashMap<Number, Box<Syscall<T>>>,
+    branches: BTreeMap<usize, BranchCount>,
+    digit_separator: Option<char>,
+    unbuffered: bool,
+    data_loaded: bool,
+    call_stack: Vec<Number>,
+    max_call_depth: usize,
+    output_width: Option<u32>,
+    memory_mode: MemoryMode,
+    max_steps: Option<u64>,
+    steps: u64,
+    max_output: Option<usize>,
+    output_len: usize,
+    max_registers: Option<usize>,
+    code_readable: bool,
 }
 
-trait OperandEval<T> {
-    fn eval<'a>(&'a mut self, i: &'a T) -> Number;
+/// Host callback invoked by `syscall N`.
+pub type Syscall<T> = dyn for<'a> FnMut(&mut MachineState<'a, T>, Number) -> RunResult<()>;
+
+#[derive(Debug)]
+pub enum RunError {
+    InvalidProgramCounter(Number),
+    InvalidJumpTarget(Number),
+    RegisterLimit(Number),
+    InvalidOutputChar(Number),
+    /// The base of `putn` is not between 2 and 36.
+    InvalidRadix(Number),
+    UnknownSyscall(Number),
+    /// `call` went deeper than the configured limit.
+    StackOverflow(usize),
+    /// `ret` without a matching `call`.
+    StackUnderflow,
+    /// The flag given to `run_cancellable` was set.
+    Cancelled,
+    /// `loadcode` ran on a machine that doesn't allow reading code.
+    CodeNotReadable,
+    /// More instructions ran than `set_max_steps` allows.
+    StepLimit(u64),
+    /// The program printed more characters than `set_max_output` allows.
+    OutputLimit(usize),
+    /// The program used more registers than `set_max_registers` allows.
+    MemoryLimit(usize),
+    Output(std::io::Error),
+    Input(std::io::Error),
+    Line(usize, Box<RunError>),
+}
+
+impl RunError {
+    fn at(self, line: Option<usize>) -> RunError {
+        match line {
+            Some(line) => RunError::Line(line, Box::new(self)),
+            None => self,
+        }
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            RunError::Line(line, _) => Some(*line),
+            _ => None,
+        }
+    }
+
+    pub fn cause(&self) -> &RunError {
+        match self {
+            RunError::Line(_, e) => e.cause(),
+            _ => self,
+        }
+    }
 }
 
-impl<'b, T: std::io::Write> OperandEval<Index> for MachineState<'b, T> {
-    fn eval<'a>(&'a mut self, i: &'a Index) -> Number {
-        match &i {
-            &Index::Direct(ref x) => x.clone(),
-            &Index::Indirect(ref x) => self.register(x),
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::InvalidProgramCounter(n) => write!(f, "Invalid program counter {}", n),
+            RunError::InvalidJumpTarget(n) => write!(f, "Invalid jump target {}", n),
+            RunError::RegisterLimit(n) => write!(f, "Too big register number {}", n),
+            RunError::InvalidOutputChar(n) => write!(f, "Invalid character code {}", n),
+            RunError::InvalidRadix(n) => write!(f, "Invalid radix {}", n),
+            RunError::UnknownSyscall(n) => write!(f, "Unknown syscall {}", n),
+            RunError::StackOverflow(depth) => {
+                write!(f, "Call stack overflow (limit {})", depth)
+            }
+            RunError::StackUnderflow => write!(f, "ret without a matching call"),
+            RunError::Cancelled => write!(f, "Cancelled"),
+            RunError::CodeNotReadable => write!(f, "loadcode is not enabled"),
+            RunError::StepLimit(max) => write!(f, "Step limit of {} exceeded", max),
+            RunError::OutputLimit(max) => write!(f, "Output limit of {} exceeded", max),
+            RunError::MemoryLimit(max) => write!(f, "Memory limit of {} registers exceeded", max),
+            RunError::Output(e) => write!(f, "Output error: {}", e),
+            RunError::Input(e) => write!(f, "Input error: {}", e),
+            RunError::Line(line, e) => write!(f, "line {}: {}", line, e),
         }
     }
 }
 
-impl<'b, T: std::io::Write> OperandEval<Value> for MachineState<'b, T> {
-    fn eval<'a>(&'a mut self, i: &'a Value) -> Number {
-        match &i {
-            &Value::Immediate(ref x) => x.clone(),
-            &Value::Register(ref x) => self.register(x),
-            &Value::Pointer(ref x) => self.register(&self.register(x)),
-            &Value::ProgramCounter => self.program_counter.clone(),
-            _ => panic!("Invalid operand"),
+impl std::error::Error for RunError {}
+
+impl From<std::io::Error> for RunError {
+    fn from(e: std::io::Error) -> RunError {
+        RunError::Output(e)
+    }
+}
+
+type RunResult<T> = std::result::Result<T, RunError>;
+
+/// How register numbers map to storage.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MemoryMode {
+    /// Every register number up to the limit has its own register.
+    #[default]
+    Growing,
+    /// Register numbers are taken modulo the size, like a tape joined into a
+    /// ring, so at most that many registers are ever stored. Negative numbers
+    /// still read as zero and can't be written.
+    Wrapping(usize),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RunState {
+    Halted(Number),
+    Stopped(usize),
+    Paused {
+        pc: usize,
+        index: usize,
+        old: Number,
+        new: Number,
+    },
+}
+
+/// Why `run_status` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltReason {
+    /// The program executed `halt`, or `echo` at the end of input.
+    ExplicitHalt,
+    /// The last instruction went on to the address after it.
+    FellOffEnd,
+    StepLimit,
+    OutputLimit,
+    MemoryLimit,
+    Cancelled,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RunStats {
+    counts: BTreeMap<&'static str, u64>,
+}
+
+impl RunStats {
+    fn record(&mut self, statement: &Statement) {
+        *self.counts.entry(statement.mnemonic()).or_insert(0) += 1;
+    }
+
+    pub fn count(&self, mnemonic: &str) -> u64 {
+        self.counts.get(mnemonic).cloned().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.counts
+            .iter()
+            .map(|(&mnemonic, &count)| (mnemonic, count))
+    }
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<8}{:>12}", "mnemonic", "count")?;
+        for (mnemonic, count) in self.iter() {
+            writeln!(f, "{:<8}{:>12}", mnemonic, count)?;
+        }
+        writeln!(f, "{:<8}{:>12}", "total", self.total())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Throughput {
+    pub instructions: u64,
+    pub elapsed: std::time::Duration,
+}
+
+impl Throughput {
+    pub fn per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.instructions as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "instructions: {}", self.instructions)?;
+        writeln!(f, "elapsed: {:.6}s", self.elapsed.as_secs_f64())?;
+        writeln!(f, "instructions/s: {:.0}", self.per_second())
+    }
+}
+
+impl<'a, T: Output> fmt::Debug for MachineState<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let zero = Number::from(0);
+        let mut registers: BTreeMap<usize, &Number> = self
+            .registers
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| **value != zero)
+            .collect();
+        registers.extend(
+            self.sparse_registers
+                .iter()
+                .filter(|(_, value)| **value != zero)
+                .map(|(&index, value)| (index, value)),
+        );
+        f.debug_struct("MachineState")
+            .field("program_counter", &format_args!("{}", self.program_counter))
+            .field("registers", &RegisterDump(registers))
+            .finish()
+    }
+}
+
+struct RegisterDump<'a>(BTreeMap<usize, &'a Number>);
+
+impl<'a> fmt::Debug for RegisterDump<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (index, value) in &self.0 {
+            map.entry(index, &format_args!("{}", value));
         }
+        map.finish()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BranchCount {
+    pub taken: u64,
+    pub fallthrough: u64,
+}
+
+trait OperandEval<T> {
+    fn eval<'a>(&'a mut self, i: &'a T) -> RunResult<Number>;
+}
+
+impl<'b, T: Output> OperandEval<Index> for MachineState<'b, T> {
+    fn eval<'a>(&'a mut self, i: &'a Index) -> RunResult<Number> {
+        Ok(match i {
+            Index::Direct(x) => x.clone(),
+            Index::Indirect(x) => self.register(x),
+            Index::Pointer(x) => {
+                let x = self.register(x);
+                self.register(&x)
+            }
+        })
+    }
+}
+
+impl<'b, T: Output> OperandEval<Value> for MachineState<'b, T> {
+    fn eval<'a>(&'a mut self, i: &'a Value) -> RunResult<Number> {
+        Ok(match i {
+            Value::Immediate(x) => x.clone(),
+            Value::Register(x) => self.register(x),
+            // Reads never fail: a pointer to a negative or too big register
+            // reads as zero, like any other unset register.
+            Value::Pointer(x) => self.register(&self.register(x)),
+            Value::ProgramCounter => self.program_counter.clone(),
+            _ => panic!("Invalid operand"),
+        })
     }
 }
 
-impl<'b, T: std::io::Write> OperandEval<Address> for MachineState<'b, T> {
-    fn eval<'a>(&'a mut self, i: &'a Address) -> Number {
-        match &i {
-            &Address::Immediate(ref x) => x.clone(),
-            &Address::Register(ref x) => self.register(x),
-            &Address::ProgramCounter => self.program_counter.clone(),
+impl<'b, T: Output> OperandEval<Address> for MachineState<'b, T> {
+    fn eval<'a>(&'a mut self, i: &'a Address) -> RunResult<Number> {
+        let address = match i {
+            Address::Immediate(x) => x.clone(),
+            Address::Register(x) => self.register(x),
+            Address::ProgramCounter => self.program_counter.clone(),
             _ => panic!("Invalid operand"),
+        };
+        if address.sign() == num_bigint::Sign::Minus {
+            Err(RunError::InvalidJumpTarget(address))
+        } else {
+            Ok(address)
+        }
+    }
+}
+
+impl MachineState<'static, NullOutput> {
+    /// A machine whose output instructions print nothing.
+    pub fn new_null() -> MachineState<'static, NullOutput> {
+        MachineState::with_output(OutputSlot::Owned(NullOutput))
+    }
+}
+
+/// Collects the limits and output settings of a machine so that they can be
+/// set up in one expression instead of a call to each setter.
+#[derive(Debug, Clone)]
+pub struct MachineBuilder {
+    max_steps: Option<u64>,
+    max_output: Option<usize>,
+    max_registers: Option<usize>,
+    max_call_depth: usize,
+    memory_mode: MemoryMode,
+    output_width: Option<u32>,
+    digit_separator: Option<char>,
+    unbuffered: bool,
+    code_readable: bool,
+}
+
+impl Default for MachineBuilder {
+    fn default() -> MachineBuilder {
+        MachineBuilder {
+            max_steps: None,
+            max_output: None,
+            max_registers: None,
+            max_call_depth: DEFAULT_CALL_DEPTH,
+            memory_mode: MemoryMode::Growing,
+            output_width: None,
+            digit_separator: None,
+            unbuffered: false,
+            code_readable: false,
         }
     }
 }
 
-impl<'b, T: std::io::Write> MachineState<'b, T> {
+impl MachineBuilder {
+    pub fn new() -> MachineBuilder {
+        MachineBuilder::default()
+    }
+
+    pub fn max_steps(mut self, max: u64) -> MachineBuilder {
+        self.max_steps = Some(max);
+        self
+    }
+
+    pub fn max_output(mut self, max: usize) -> MachineBuilder {
+        self.max_output = Some(max);
+        self
+    }
+
+    pub fn max_registers(mut self, max: usize) -> MachineBuilder {
+        self.max_registers = Some(max);
+        self
+    }
+
+    pub fn max_call_depth(mut self, depth: usize) -> MachineBuilder {
+        self.max_call_depth = depth;
+        self
+    }
+
+    pub fn memory_mode(mut self, mode: MemoryMode) -> MachineBuilder {
+        self.memory_mode = mode;
+        self
+    }
+
+    pub fn output_width(mut self, width: u32) -> MachineBuilder {
+        self.output_width = Some(width);
+        self
+    }
+
+    pub fn digit_grouping(mut self, separator: char) -> MachineBuilder {
+        self.digit_separator = Some(separator);
+        self
+    }
+
+    pub fn unbuffered(mut self, unbuffered: bool) -> MachineBuilder {
+        self.unbuffered = unbuffered;
+        self
+    }
+
+    pub fn code_readable(mut self, readable: bool) -> MachineBuilder {
+        self.code_readable = readable;
+        self
+    }
+
+    /// Panics if the memory mode wraps with a size of zero.
+    pub fn build<T: Output>(self, output: &mut T) -> MachineState<'_, T> {
+        let mut machine = MachineState::new(output);
+        machine.set_max_steps(self.max_steps);
+        machine.set_max_output(self.max_output);
+        machine.set_max_registers(self.max_registers);
+        machine.set_max_call_depth(self.max_call_depth);
+        machine.set_memory_mode(self.memory_mode);
+        machine.set_output_width(self.output_width);
+        machine.set_digit_grouping(self.digit_separator);
+        machine.set_unbuffered(self.unbuffered);
+        machine.set_code_readable(self.code_readable);
+        machine
+    }
+}
+
+impl<'b, T: Output> MachineState<'b, T> {
     pub fn new(o: &'b mut T) -> MachineState<'b, T> {
+        MachineState::with_output(OutputSlot::Borrowed(o))
+    }
+
+    fn with_output(output: OutputSlot<'b, T>) -> MachineState<'b, T> {
         MachineState {
             registers: vec![Number::from(0)], // Vec::with_capacity(FIRST_MEMORY_SIZE),
+            written: vec![false],
+            sparse_registers: HashMap::new(),
             program_counter: Default::default(),
-            output: o,
+            output,
+            input: None,
+            stats: None,
+            syscalls: HashMap::new(),
+            branches: BTreeMap::new(),
+            digit_separator: None,
+            unbuffered: false,
+            data_loaded: false,
+            call_stack: Vec::new(),
+            max_call_depth: DEFAULT_CALL_DEPTH,
+            output_width: None,
+            memory_mode: MemoryMode::Growing,
+            max_steps: None,
+            steps: 0,
+            max_output: None,
+            output_len: 0,
+            max_registers: None,
+            code_readable: false,
+        }
+    }
+
+    /// Like `new`, but reserves room for `capacity` registers up front so a
+    /// program with a known footprint doesn't grow the register file step by
+    /// step. Registers past the dense range are stored sparsely and are not
+    /// reserved.
+    pub fn with_capacity(o: &'b mut T, capacity: usize) -> MachineState<'b, T> {
+        let mut machine = MachineState::new(o);
+        machine
+            .registers
+            .reserve(capacity.min(DENSE_LIMIT).saturating_sub(1));
+        machine
+    }
+
+    /// Clears registers, program counter and counters. Output, input and
+    /// syscall handlers are kept.
+    pub fn reset(&mut self) {
+        self.registers = vec![Number::from(0)];
+        self.written = vec![false];
+        self.sparse_registers.clear();
+        self.program_counter = Default::default();
+        self.branches.clear();
+        self.data_loaded = false;
+        self.call_stack.clear();
+        self.steps = 0;
+        self.output_len = 0;
+    }
+
+    pub fn register_syscall<F>(&mut self, number: Number, handler: F)
+    where
+        F: for<'a> FnMut(&mut MachineState<'a, T>, Number) -> RunResult<()> + 'static,
+    {
+        self.syscalls.insert(number, Box::new(handler));
+    }
+
+    /// Makes `putn` and `puth` separate every three digits with the given
+    /// character, e.g. `1,000,000`.
+    pub fn set_digit_grouping(&mut self, separator: Option<char>) {
+        self.digit_separator = separator;
+    }
+
+    /// Makes `putn` and `puth` print values as unsigned numbers of the given
+    /// bit width, so with 8 bits `-1` prints as `255` and `256` as `0`.
+    pub fn set_output_width(&mut self, width: Option<u32>) {
+        self.output_width = width;
+    }
+
+    /// Fails the run with `RunError::StepLimit` instead of executing more
+    /// than `max` instructions.
+    pub fn set_max_steps(&mut self, max: Option<u64>) {
+        self.max_steps = max;
+    }
+
+    /// Fails the run with `RunError::OutputLimit` instead of printing more
+    /// than `max` characters.
+    pub fn set_max_output(&mut self, max: Option<usize>) {
+        self.max_output = max;
+    }
+
+    /// Fails the run with `RunError::MemoryLimit` instead of allocating more
+    /// than `max` registers, counted as `allocated_registers` does.
+    pub fn set_max_registers(&mut self, max: Option<usize>) {
+        self.max_registers = max;
+    }
+
+    /// Lets `loadcode` read the program's own instructions. Off by default,
+    /// which keeps code and registers apart.
+    pub fn set_code_readable(&mut self, readable: bool) {
+        self.code_readable = readable;
+    }
+
+    /// Panics if a wrapping size is zero.
+    pub fn set_memory_mode(&mut self, mode: MemoryMode) {
+        assert!(
+            mode != MemoryMode::Wrapping(0),
+            "wrapping memory needs at least one register"
+        );
+        self.memory_mode = mode;
+    }
+
+    /// Limits how many `call`s may be pending at once. Going deeper fails
+    /// with `RunError::StackOverflow`.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Flushes the output after every `putc`, `putn`, `puth` and `puts` so
+    /// that interactive programs show their prompts immediately.
+    pub fn set_unbuffered(&mut self, unbuffered: bool) {
+        self.unbuffered = unbuffered;
+    }
+
+    pub fn set_output(&mut self, output: &'b mut T) {
+        self.output = OutputSlot::Borrowed(output);
+    }
+
+    pub fn set_input(&mut self, input: &'b mut dyn std::io::BufRead) {
+        self.input = Some(input);
+    }
+
+    pub fn run_with_stats(&mut self, program: &Program) -> RunResult<(Number, RunStats)> {
+        self.stats = Some(RunStats::default());
+        let result = self.run(program);
+        let stats = self.stats.take().unwrap_or_default();
+        Ok((result?, stats))
+    }
+
+    /// Runs like `run` and also returns how many instructions were executed,
+    /// without the per-mnemonic bookkeeping of `run_with_stats`.
+    pub fn run_counting(&mut self, program: &Program) -> RunResult<(Number, u64)> {
+        if program.is_empty() {
+            return Err(RunError::InvalidProgramCounter(
+                self.program_counter.clone(),
+            ));
+        }
+        let mut count = 1;
+        while !self.advance(program)? {
+            count += 1;
+        }
+        Ok((self.register(&Number::from(0)), count))
+    }
+
+    pub fn run_with_throughput(&mut self, program: &Program) -> RunResult<(Number, Throughput)> {
+        let start = std::time::Instant::now();
+        let (result, stats) = self.run_with_stats(program)?;
+        let throughput = Throughput {
+            instructions: stats.total(),
+            elapsed: start.elapsed(),
+        };
+        Ok((result, throughput))
+    }
+
+    pub fn run(&mut self, program: &Program) -> RunResult<Number> {
+        if program.is_empty() {
+            return Err(RunError::InvalidProgramCounter(
+                self.program_counter.clone(),
+            ));
+        }
+        while !self.advance(program)? {}
+        Ok(self.register(&Number::from(0)))
+    }
+
+    /// Runs like `run` but gives up with `RunError::Cancelled` once another
+    /// thread sets the flag. The flag is checked every
+    /// `CANCEL_CHECK_INTERVAL` instructions, so a few more may run after it
+    /// is set.
+    pub fn run_cancellable(
+        &mut self,
+        program: &Program,
+        cancelled: &AtomicBool,
+    ) -> RunResult<Number> {
+        if program.is_empty() {
+            return Err(RunError::InvalidProgramCounter(
+                self.program_counter.clone(),
+            ));
+        }
+        loop {
+            if cancelled.load(Ordering::Relaxed) {
+                return Err(RunError::Cancelled);
+            }
+            for _ in 0..CANCEL_CHECK_INTERVAL {
+                if self.advance(program)? {
+                    return Ok(self.register(&Number::from(0)));
+                }
+            }
+        }
+    }
+
+    /// Runs like `run`, but stops without an error when the program goes
+    /// past its last instruction, and reports the step, output and memory
+    /// limits and cancellation as the reason for stopping rather than as
+    /// errors.
+    /// The flag, if any, is checked as in `run_cancellable`.
+    pub fn run_status(
+        &mut self,
+        program: &Program,
+        cancelled: Option<&AtomicBool>,
+    ) -> RunResult<(Number, HaltReason)> {
+        if program.is_empty() {
+            return Err(RunError::InvalidProgramCounter(
+                self.program_counter.clone(),
+            ));
         }
+        let mut count: u32 = 0;
+        let reason = loop {
+            if self.program_counter.to_usize() == Some(program.len()) {
+                break HaltReason::FellOffEnd;
+            }
+            if count.is_multiple_of(CANCEL_CHECK_INTERVAL)
+                && cancelled.is_some_and(|flag| flag.load(Ordering::Relaxed))
+            {
+                break HaltReason::Cancelled;
+            }
+            count = count.wrapping_add(1);
+            match self.advance(program) {
+                Ok(true) => break HaltReason::ExplicitHalt,
+                Ok(false) => {}
+                Err(e) => match e.cause() {
+                    RunError::StepLimit(_) => break HaltReason::StepLimit,
+                    RunError::OutputLimit(_) => break HaltReason::OutputLimit,
+                    RunError::MemoryLimit(_) => break HaltReason::MemoryLimit,
+                    RunError::Cancelled => break HaltReason::Cancelled,
+                    _ => return Err(e),
+                },
+            }
+        };
+        Ok((self.register(&Number::from(0)), reason))
     }
 
-    pub fn run(&mut self, program: &Program) -> Number {
+    pub fn run_with_watch(
+        &mut self,
+        program: &Program,
+        watched: &HashSet<usize>,
+    ) -> RunResult<RunState> {
+        self.load_data(program)?;
         loop {
-            let program_counter = self.program_counter.to_usize();
-            let program_counter = match program_counter {
-                None => {
-                    eprintln!("Invalid program counter {}", self.program_counter);
-                    std::process::exit(4);
+            let pc = self.fetch(program)?;
+            let before: Vec<(usize, Number)> = watched
+                .iter()
+                .map(|&index| (index, self.register(&Number::from(index))))
+                .collect();
+            if self.step(program)? {
+                return Ok(RunState::Halted(self.register(&Number::from(0))));
+            }
+            for (index, old) in before {
+                let new = self.register(&Number::from(index));
+                if new != old {
+                    return Ok(RunState::Paused {
+                        pc,
+                        index,
+                        old,
+                        new,
+                    });
                 }
-                Some(ref a) if a > &program.len() => {
-                    eprintln!("Invalid program counter {}", self.program_counter);
-                    std::process::exit(4);
+            }
+        }
+    }
+
+    /// Runs until the predicate holds before an instruction is dispatched.
+    /// The instruction at the returned address has not been executed yet.
+    pub fn run_until(
+        &mut self,
+        program: &Program,
+        predicate: &dyn Fn(&MachineState<'b, T>) -> bool,
+    ) -> RunResult<RunState> {
+        self.load_data(program)?;
+        loop {
+            let pc = self.fetch(program)?;
+            if predicate(self) {
+                return Ok(RunState::Stopped(pc));
+            }
+            if self.step(program)? {
+                return Ok(RunState::Halted(self.register(&Number::from(0))));
+            }
+        }
+    }
+
+    // Stores the program's `data` blocks the first time the machine touches
+    // the program.
+    fn load_data(&mut self, program: &Program) -> RunResult<()> {
+        if self.data_loaded {
+            return Ok(());
+        }
+        self.data_loaded = true;
+        for (index, values) in program.data() {
+            for (offset, value) in values.iter().enumerate() {
+                *self.register_mut(&Number::from(index + offset))? = value.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes a single instruction and returns whether the machine halted.
+    pub fn step(&mut self, program: &Program) -> RunResult<bool> {
+        let program_counter = self.begin_step(program)?;
+        self.execute(program, &program[program_counter])
+            .map_err(|e| e.at(program.line(program_counter)))
+    }
+
+    // Like `step`, but takes the prepared form of the instruction when it
+    // has one. The run loops go through here; `step` stays on the plain
+    // interpreter so the two can be checked against each other.
+    fn advance(&mut self, program: &Program) -> RunResult<bool> {
+        let program_counter = self.begin_step(program)?;
+        self.execute_prepared(program, program_counter)
+            .map_err(|e| e.at(program.line(program_counter)))
+    }
+
+    // The bookkeeping every instruction goes through before it is executed.
+    fn begin_step(&mut self, program: &Program) -> RunResult<usize> {
+        self.load_data(program)?;
+        let program_counter = self.fetch(program)?;
+        if let Some(max) = self.max_steps {
+            if self.steps >= max {
+                return Err(RunError::StepLimit(max));
+            }
+        }
+        self.steps += 1;
+        if let Some(ref mut stats) = self.stats {
+            stats.record(&program[program_counter]);
+        }
+        Ok(program_counter)
+    }
+
+    fn execute_prepared(&mut self, program: &Program, pc: usize) -> RunResult<bool> {
+        match &program.ops()[pc] {
+            Op::Incr { index, value } => {
+                self.program_counter += 1;
+                let value = self.operand(value);
+                *self.register_mut_at(*index)? += value.as_ref();
+            }
+            Op::Decr {
+                index,
+                target,
+                value,
+            } => {
+                self.program_counter += 1;
+                let value = self.operand(value);
+                let taken = match self.register_if_set(*index) {
+                    Some(register) => register < value.as_ref(),
+                    None => value.is_positive(),
+                };
+                let count = self.branches.entry(pc).or_default();
+                if taken {
+                    count.taken += 1;
+                    self.program_counter = Number::from(*target);
+                } else {
+                    count.fallthrough += 1;
+                    *self.register_mut_at(*index)? -= value.as_ref();
                 }
-                Some(a) => a,
-            };
-            match &program[program_counter] {
-                &Statement::Incr(ref index, ref value) => {
-                    self.program_counter += 1;
-                    let index = &self.eval(index);
-                    if index.sign() != num_bigint::Sign::Minus {
-                        let value = &self.eval(value);
-                        *self.register_mut(index) += value;
+            }
+            Op::Jump { target } => {
+                self.branches.entry(pc).or_default().taken += 1;
+                self.program_counter = Number::from(*target);
+            }
+            Op::Save { index, value } => {
+                self.program_counter += 1;
+                let value = self.operand(value).into_owned();
+                *self.register_mut_at(*index)? = value;
+            }
+            Op::Interpret => return self.execute(program, &program[pc]),
+        }
+        Ok(false)
+    }
+
+    fn operand<'p>(&self, operand: &'p Operand) -> Cow<'p, Number> {
+        match operand {
+            Operand::Immediate(n) => Cow::Borrowed(n),
+            Operand::Register(index) => {
+                Cow::Owned(self.register_if_set(*index).cloned().unwrap_or_default())
+            }
+        }
+    }
+
+    fn fetch(&self, program: &Program) -> RunResult<usize> {
+        match self.program_counter.to_usize() {
+            Some(a) if a < program.len() => Ok(a),
+            _ => Err(RunError::InvalidProgramCounter(
+                self.program_counter.clone(),
+            )),
+        }
+    }
+
+    fn execute(&mut self, program: &Program, statement: &Statement) -> RunResult<bool> {
+        match statement {
+            Statement::Incr(index, value) => {
+                self.program_counter += 1;
+                let index = &self.eval(index)?;
+                if index.sign() != num_bigint::Sign::Minus {
+                    let value = &self.eval(value)?;
+                    *self.register_mut(index)? += value;
+                }
+            }
+            Statement::Decr(index, address, value) => {
+                let pc = self.program_counter.to_usize().unwrap_or_default();
+                self.program_counter += 1;
+                let index = &self.eval(index)?;
+                let value = &self.eval(value)?;
+                let taken = self.register(index) < *value;
+                let count = self.branches.entry(pc).or_default();
+                if taken {
+                    count.taken += 1;
+                    self.program_counter = self.eval(address)?;
+                } else {
+                    count.fallthrough += 1;
+                    *self.register_mut(index)? -= value;
+                }
+            }
+            Statement::Decrb(index, borrow, value) => {
+                self.program_counter += 1;
+                let index = &self.eval(index)?;
+                let borrow = &self.eval(borrow)?;
+                let value = &self.eval(value)?;
+                let difference = self.register(index) - value;
+                if difference.sign() == num_bigint::Sign::Minus {
+                    *self.register_mut(index)? = Number::from(0);
+                    *self.register_mut(borrow)? = Number::from(1);
+                } else {
+                    *self.register_mut(index)? = difference;
+                    *self.register_mut(borrow)? = Number::from(0);
+                }
+            }
+            Statement::Save(index, value) => {
+                self.program_counter += 1;
+                let index = &self.eval(index)?;
+                let value = self.eval(value)?;
+                *self.register_mut(index)? = value;
+            }
+            Statement::Loadcode(index, address) => {
+                if !self.code_readable {
+                    return Err(RunError::CodeNotReadable);
+                }
+                self.program_counter += 1;
+                let index = &self.eval(index)?;
+                let address = self.eval(address)?;
+                // Like a register out of range, an address outside the program
+                // reads as zero.
+                let code = match address.to_usize() {
+                    Some(address) if address < program.len() => {
+                        crate::bytecode::instruction_code(&program[address])
                     }
+                    _ => Number::from(0),
+                };
+                *self.register_mut(index)? = code;
+            }
+            Statement::Savepc(index) => {
+                let pc = self.program_counter.clone();
+                self.program_counter += 1;
+                let index = &self.eval(index)?;
+                *self.register_mut(index)? = pc;
+            }
+            Statement::Putc(value) => {
+                self.program_counter += 1;
+                let value = self.eval(value)?;
+                self.write_char(value)?;
+                self.flush_if_unbuffered()?;
+            }
+            Statement::Putn(value, base) => {
+                self.program_counter += 1;
+                let value = self.eval(value)?;
+                let value = self.wrap_to_width(value);
+                let base = self.eval(base)?;
+                let radix = match base.to_u32() {
+                    Some(radix) if (2..=36).contains(&radix) => radix,
+                    _ => return Err(RunError::InvalidRadix(base)),
+                };
+                let separator = self.digit_separator;
+                self.charge_output(|| printed_len(&value, radix, separator))?;
+                match self.digit_separator {
+                    Some(separator) => self
+                        .output
+                        .write_str(&group_digits(&value.to_str_radix(radix), separator))?,
+                    None if radix == 10 => self.output.write_number(&value)?,
+                    None => self.output.write_str(&value.to_str_radix(radix))?,
+                }
+                self.flush_if_unbuffered()?;
+            }
+            Statement::Puth(value) => {
+                self.program_counter += 1;
+                let value = self.eval(value)?;
+                let value = self.wrap_to_width(value);
+                let separator = self.digit_separator;
+                self.charge_output(|| printed_len(&value, 16, separator))?;
+                match self.digit_separator {
+                    Some(separator) => self
+                        .output
+                        .write_str(&group_digits(&value.to_str_radix(16), separator))?,
+                    None => self.output.write_hex(&value)?,
                 }
-                &Statement::Decr(ref index, ref address, ref value) => {
-                    self.program_counter += 1;
-                    let index = &self.eval(index);
-                    let address = self.eval(address);
-                    let value = &self.eval(value);
-                    if self.register(index) >= *value {
-                        *self.register_mut(index) -= value;
-                    } else {
-                        self.program_counter = address;
+                self.flush_if_unbuffered()?;
+            }
+            Statement::Puts(index) => {
+                self.program_counter += 1;
+                let start = self.eval(index)?;
+                // A wrapped ring without a zero register would print forever,
+                // so stop after one full pass over it.
+                let pass = match self.memory_mode {
+                    MemoryMode::Growing => usize::MAX,
+                    MemoryMode::Wrapping(size) => size,
+                };
+                for offset in 0..pass {
+                    let value = self.register(&(&start + offset));
+                    if value == Number::from(0) {
+                        break;
                     }
+                    self.write_char(value)?;
                 }
-                &Statement::Save(ref index, ref value) => {
-                    self.program_counter += 1;
-                    let index = &self.eval(index);
-                    let value = self.eval(value);
-                    *self.register_mut(index) = value;
+                self.flush_if_unbuffered()?;
+            }
+            Statement::Gets(index) => {
+                self.program_counter += 1;
+                let mut index = self.eval(index)?;
+                let mut line = String::new();
+                // Make a prompt written before reading visible to the user.
+                self.output.flush()?;
+                if let Some(ref mut input) = self.input {
+                    input.read_line(&mut line).map_err(RunError::Input)?;
                 }
-                &Statement::Putc(ref value) => {
-                    self.program_counter += 1;
-                    let value = self.eval(value);
-                    write!(
-                        self.output,
-                        "{}",
-                        std::char::from_u32(value.to_u32().unwrap()).unwrap()
-                    )
-                    .unwrap();
+                let line = line.strip_suffix('\n').unwrap_or(&line);
+                let line = line.strip_suffix('\r').unwrap_or(line);
+                for ch in line.chars() {
+                    *self.register_mut(&index)? = Number::from(ch as u32);
+                    index += 1;
                 }
-                &Statement::Putn(ref value) => {
-                    self.program_counter += 1;
-                    let value = self.eval(value);
-                    write!(self.output, "{}", value).unwrap();
+                *self.register_mut(&index)? = Number::from(0);
+            }
+            Statement::Echo => {
+                self.output.flush()?;
+                let ch = match self.input {
+                    Some(ref mut input) => read_char(&mut **input).map_err(RunError::Input)?,
+                    None => None,
+                };
+                match ch {
+                    Some(ch) => {
+                        self.program_counter += 1;
+                        self.write_char(Number::from(u32::from(ch)))?;
+                        self.flush_if_unbuffered()?;
+                    }
+                    None => return Ok(true),
                 }
-                &Statement::Halt => {
-                    break;
+            }
+            Statement::Nop => self.program_counter += 1,
+            Statement::Call(address) => {
+                if self.call_stack.len() >= self.max_call_depth {
+                    return Err(RunError::StackOverflow(self.max_call_depth));
                 }
+                let target = self.eval(address)?;
+                self.program_counter += 1;
+                self.call_stack.push(self.program_counter.clone());
+                self.program_counter = target;
+            }
+            Statement::Ret => {
+                self.program_counter = self.call_stack.pop().ok_or(RunError::StackUnderflow)?;
+            }
+            Statement::Halt => {
+                self.output.flush()?;
+                return Ok(true);
+            }
+            Statement::Ext(number) => {
+                self.program_counter += 1;
+                let mut handler = self
+                    .syscalls
+                    .remove(number)
+                    .ok_or_else(|| RunError::UnknownSyscall(number.clone()))?;
+                let result = handler(self, number.clone());
+                self.syscalls.insert(number.clone(), handler);
+                result?;
+            }
+        }
+        Ok(false)
+    }
+
+    fn wrap_to_width(&self, value: Number) -> Number {
+        match self.output_width {
+            Some(width) => {
+                let modulus = Number::from(1) << width as usize;
+                ((value % &modulus) + &modulus) % &modulus
             }
+            None => value,
+        }
+    }
+
+    fn flush_if_unbuffered(&mut self) -> RunResult<()> {
+        if self.unbuffered {
+            self.output.flush()?;
         }
+        Ok(())
+    }
 
-        self.register(&Number::from(0))
+    fn write_char(&mut self, value: Number) -> RunResult<()> {
+        let ch = value
+            .to_u32()
+            .and_then(std::char::from_u32)
+            .ok_or(RunError::InvalidOutputChar(value))?;
+        self.charge_output(|| 1)?;
+        self.output.write_char(ch)?;
+        Ok(())
     }
 
-    fn register(&self, num: &Number) -> Number {
-        let num = num.to_usize();
-        match num {
-            Some(x) => {
-                if self.registers.len() <= x {
-                    Number::from(0)
-                } else {
-                    self.registers[x].clone()
-                }
+    // Counts characters about to be printed against the output limit. The
+    // length is only worked out when there is a limit.
+    fn charge_output(&mut self, len: impl FnOnce() -> usize) -> RunResult<()> {
+        if let Some(max) = self.max_output {
+            self.output_len += len();
+            if self.output_len > max {
+                return Err(RunError::OutputLimit(max));
             }
+        }
+        Ok(())
+    }
+
+    /// How often each `decr`, keyed by its address, jumped or fell through.
+    pub fn branch_histogram(&self) -> &BTreeMap<usize, BranchCount> {
+        &self.branches
+    }
+
+    pub fn output(&self) -> &T {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut T {
+        &mut self.output
+    }
+
+    pub fn program_counter(&self) -> &Number {
+        &self.program_counter
+    }
+
+    pub fn set_program_counter(&mut self, pc: usize) {
+        self.program_counter = Number::from(pc);
+    }
+
+    pub fn read_register(&self, index: usize) -> Number {
+        self.register(&Number::from(index))
+    }
+
+    /// The register's value, or `None` if the program never wrote to it or
+    /// to any register above it in the dense range. Execution still reads
+    /// such registers as zero.
+    pub fn register_if_set(&self, index: usize) -> Option<&Number> {
+        let index = self.slot_at(index);
+        self.registers
+            .get(index)
+            .or_else(|| self.sparse_registers.get(&index))
+    }
+
+    pub fn write_register(&mut self, index: usize, value: Number) -> RunResult<()> {
+        *self.register_mut(&Number::from(index))? = value;
+        Ok(())
+    }
+
+    /// Every register the machine has written so far, in ascending order.
+    /// A register written with 0 counts; one that was only read does not.
+    pub fn touched_registers(&self) -> Vec<usize> {
+        let mut touched: Vec<usize> = self
+            .written
+            .iter()
+            .enumerate()
+            .filter(|(_, &written)| written)
+            .map(|(index, _)| index)
+            .chain(self.sparse_registers.keys().copied())
+            .collect();
+        touched.sort_unstable();
+        touched
+    }
+
+    pub fn allocated_registers(&self) -> usize {
+        self.registers.len() + self.sparse_registers.len()
+    }
+
+    // Where the register with the number is stored, if it can be.
+    fn slot(&self, num: &Number) -> Option<usize> {
+        match self.memory_mode {
+            MemoryMode::Growing => num.to_usize(),
+            MemoryMode::Wrapping(_) if num.is_negative() => None,
+            MemoryMode::Wrapping(size) => (num % size).to_usize(),
+        }
+    }
+
+    fn slot_at(&self, index: usize) -> usize {
+        match self.memory_mode {
+            MemoryMode::Growing => index,
+            MemoryMode::Wrapping(size) => index % size,
+        }
+    }
+
+    fn register(&self, num: &Number) -> Number {
+        match self.slot(num) {
+            Some(x) if x < self.registers.len() => self.registers[x].clone(),
+            Some(x) => self.sparse_registers.get(&x).cloned().unwrap_or_default(),
             None => Number::from(0),
         }
     }
 
-    fn register_mut(&mut self, num: &Number) -> &mut Number {
-        let num = num.to_usize();
-        match num {
-            Some(x) => {
-                if x > MEMORY_LIMIT {
-                    eprintln!("Too big register number");
-                    std::process::exit(5);
-                }
-                if self.registers.len() <= x {
-                    self.registers.resize_with(x + 1, Default::default);
-                }
-                &mut self.registers[x]
+    fn register_mut(&mut self, num: &Number) -> RunResult<&mut Number> {
+        match self.slot(num) {
+            Some(x) if x <= MEMORY_LIMIT => self.slot_mut(x),
+            _ => Err(RunError::RegisterLimit(num.clone())),
+        }
+    }
+
+    fn register_mut_at(&mut self, index: usize) -> RunResult<&mut Number> {
+        match self.slot_at(index) {
+            x if x <= MEMORY_LIMIT => self.slot_mut(x),
+            _ => Err(RunError::RegisterLimit(Number::from(index))),
+        }
+    }
+
+    fn slot_mut(&mut self, x: usize) -> RunResult<&mut Number> {
+        if let Some(max) = self.max_registers {
+            // How many registers storing to the slot allocates.
+            let growth = if x < DENSE_LIMIT {
+                (x + 1).saturating_sub(self.registers.len())
+            } else {
+                usize::from(!self.sparse_registers.contains_key(&x))
+            };
+            if growth > 0 && self.allocated_registers() + growth > max {
+                return Err(RunError::MemoryLimit(max));
             }
-            None => {
-                eprintln!("Too big register number");
-                std::process::exit(5);
+        }
+        if x < DENSE_LIMIT {
+            if self.registers.len() <= x {
+                self.registers.resize_with(x + 1, Default::default);
+                self.written.resize(x + 1, false);
             }
+            self.written[x] = true;
+            Ok(&mut self.registers[x])
+        } else {
+            Ok(self.sparse_registers.entry(x).or_default())
         }
     }
 }
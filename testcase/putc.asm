@@ -0,0 +1,10 @@
+; これは putc が出力する文字を確かめるためのコードです
+        putc 72     ; H
+        putc 'i'
+        putc 33     ; !
+        putc '\n'
+        putc 12354  ; あ (UTF-8 では 3 バイト)
+        putc 0      ; NUL もそのまま出力する
+        save 1, 10
+        putc [1]    ; レジスタの値を文字コードとして出力する
+        halt
@@ -0,0 +1,243 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+fn write_source(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("aaron-asm-{}-{}.asm", std::process::id(), name));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+fn exit_code(path: &PathBuf) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_aaron-asm"))
+        .arg(path)
+        .output()
+        .unwrap()
+        .status
+        .code()
+}
+
+#[test]
+fn parse_error_exit_code() {
+    let path = write_source("parse-error", "        jump 0\n");
+    assert_eq!(exit_code(&path), Some(1));
+}
+
+#[test]
+fn parse_error_keeps_stdout_clean() {
+    let path = write_source("parse-error-stdout", "        halt\n        jump 0\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_aaron-asm"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("line 2, column 9"));
+}
+
+#[test]
+fn runtime_error_exit_code() {
+    let path = write_source(
+        "runtime-error",
+        "        save 1, -3\n        decr 2, [1]\n        halt\n",
+    );
+    assert_eq!(exit_code(&path), Some(4));
+}
+
+#[test]
+fn step_limit_exit_code() {
+    let path = write_source("step-limit", "loop    decr -1, loop\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_aaron-asm"))
+        .arg("--max-steps=100")
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(10));
+}
+
+#[test]
+fn emit_ast() {
+    let path = write_source(
+        "emit-ast",
+        "        save 1, 3\nloop    decr 1, done\n        decr -1, loop\ndone    halt\n",
+    );
+    let output = Command::new(env!("CARGO_BIN_EXE_aaron-asm"))
+        .arg("--emit=ast")
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout: String = String::from_utf8(output.stdout)
+        .unwrap()
+        .split_whitespace()
+        .collect();
+    let label = stdout.find("label:Some(\"loop\",)").unwrap();
+    let line = stdout[label..].find("line:").unwrap() + label;
+    assert!(stdout[line..].starts_with("line:2,"));
+    assert!(stdout.contains("Label(\"done\",)"));
+}
+
+#[test]
+fn missing_file_exit_code() {
+    let path = std::env::temp_dir().join("aaron-asm-no-such-file.asm");
+    assert_eq!(exit_code(&path), Some(2));
+}
+
+#[test]
+fn run_bytecode() {
+    let program: aaron_asm::Program = include_str!("../testcase/factorial.asm").parse().unwrap();
+    let path = std::env::temp_dir().join(format!("aaron-asm-{}-factorial.bin", std::process::id()));
+    std::fs::write(&path, program.to_bytecode()).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_aaron-asm"))
+        .arg("--bytecode")
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "120\n");
+}
+
+#[test]
+fn emit_bytecode() {
+    let path = write_source("emit-bytecode", include_str!("../testcase/factorial.asm"));
+    let output = Command::new(env!("CARGO_BIN_EXE_aaron-asm"))
+        .args(["-c", "-b"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let program = aaron_asm::Program::from_bytecode(&output.stdout).unwrap();
+    let mut buffer = Vec::new();
+    let mut machine = aaron_asm::MachineState::new(&mut buffer);
+    assert_eq!(machine.run(&program).unwrap(), 120.into());
+}
+
+#[test]
+fn quiet_suppresses_result() {
+    let path = write_source(
+        "quiet",
+        "        save 0, 7\n        putn 42\n        halt\n",
+    );
+    let output = Command::new(env!("CARGO_BIN_EXE_aaron-asm"))
+        .arg("-q")
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "42");
+}
+
+#[test]
+fn invalid_utf8_source() {
+    let path = std::env::temp_dir().join(format!("aaron-asm-{}-binary.asm", std::process::id()));
+    std::fs::write(&path, b"        putc 65\n\xff\xfe\n        halt\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_aaron-asm"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(9));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("file is not valid UTF-8"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn closed_pipe_exits_quietly() {
+    use std::io::Read;
+    use std::process::Stdio;
+
+    let path = write_source(
+        "closed-pipe",
+        "        save 1, 1000000\nloop    putc 65 : decr 1, done : decr -1, loop\ndone    halt\n",
+    );
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aaron-asm"))
+        .arg(&path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let mut head = [0; 16];
+    stdout.read_exact(&mut head).unwrap();
+    assert_eq!(&head, b"AAAAAAAAAAAAAAAA");
+    drop(stdout);
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn format_is_idempotent() {
+    let format = |path: &PathBuf| {
+        let output = Command::new(env!("CARGO_BIN_EXE_aaron-asm"))
+            .arg("--fmt")
+            .arg(path)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let path = write_source(
+        "fmt",
+        "  ; counts down\n   save   1,3;start\nloop decr 1 ,done\n\tdecr -1,  loop:nop\ndone  halt",
+    );
+    let formatted = format(&path);
+    assert_eq!(
+        formatted,
+        "\t; counts down\n\tsave 1, 3\t;start\nloop\tdecr 1, done\n\tdecr -1, loop : nop\ndone\thalt\n"
+    );
+    let path = write_source("fmt-again", &formatted);
+    assert_eq!(format(&path), formatted);
+}
+
+#[test]
+fn unknown_argument_exit_code() {
+    let path = write_source("unknown-argument", "        halt\n");
+    let other = write_source("second-file", "        halt\n");
+    for args in [
+        vec![PathBuf::from("--bogus"), path.clone()],
+        vec![PathBuf::from("--deny-warning"), path.clone()],
+        vec![path.clone(), other],
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_aaron-asm"))
+            .args(&args)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(3), "{:?}", args);
+    }
+}
+
+#[test]
+fn profile_table_on_stderr() {
+    let path = write_source("profile", include_str!("../testcase/fizzbuzz.asm"));
+    let output = Command::new(env!("CARGO_BIN_EXE_aaron-asm"))
+        .arg("--profile")
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stdout.starts_with("0FizzBuzz\n1\n2\n3Fizz\n"));
+    assert!(!stdout.contains("mnemonic"));
+    assert!(stderr.starts_with("mnemonic       count\n"));
+    assert!(stderr.contains("\nputn             101\n"));
+    assert!(stderr.contains("\ntotal "));
+}
+
+#[test]
+fn warnings_only_on_request() {
+    let path = write_source("warnings", include_str!("../testcase/labelvalue.asm"));
+    let run = |flag: Option<&str>| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_aaron-asm"));
+        command.args(flag).arg(&path);
+        command.output().unwrap()
+    };
+    let plain = run(None);
+    assert!(plain.status.success());
+    assert!(plain.stderr.is_empty());
+    let warned = run(Some("--warnings"));
+    assert!(warned.status.success());
+    assert!(String::from_utf8_lossy(&warned.stderr).contains("unreachable"));
+    assert_eq!(warned.stdout, plain.stdout);
+    assert_eq!(run(Some("--deny-warnings")).status.code(), Some(7));
+}